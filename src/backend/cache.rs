@@ -1,15 +1,69 @@
 extern crate imagepipe;
 extern crate multicache;
 use self::multicache::MultiCache;
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
 use self::imagepipe::SRGBImage;
+use crate::backend::diskcache::{self, DiskCache};
+use crate::backend::directory;
+use crate::backend::histogram::Histogram;
+
+/// Units `Region` uses across the whole width and height of the image
+pub const REGION_UNITS: u32 = 1 << 16;
+
+/// Part of the image as it comes out of the pipeline, so after cropping and
+/// rotating, in units of `REGION_UNITS`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Region {
+  pub x: u32,
+  pub y: u32,
+  pub width: u32,
+  pub height: u32,
+}
+
+impl Region {
+  /// Left, top, right and bottom edges as fractions of the image
+  pub fn fractions(&self) -> [f64; 4] {
+    let units = REGION_UNITS as f64;
+    [
+      self.x as f64 / units,
+      self.y as f64 / units,
+      (self.x + self.width) as f64 / units,
+      (self.y + self.height) as f64 / units,
+    ]
+  }
+
+  // Narrow down the crops of the ops to just this region
+  fn crop(&self, ops: &mut imagepipe::PipelineOps) {
+    let [left, top, right, bottom] = self.fractions();
+    let crop = &mut ops.rotatecrop;
+    let width = (1.0 - crop.crop_left - crop.crop_right) as f64;
+    let height = (1.0 - crop.crop_top - crop.crop_bottom) as f64;
+    crop.crop_left += (left * width) as f32;
+    crop.crop_right += ((1.0 - right) * width) as f32;
+    crop.crop_top += (top * height) as f32;
+    crop.crop_bottom += ((1.0 - bottom) * height) as f32;
+  }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct RequestedImage {
   pub file: String,
+  // Size the whole image needs to be shown at, even when only a region of it
+  // gets rendered
   pub width: u32,
   pub height: u32,
   pub ops: Option<imagepipe::PipelineOps>,
+  pub region: Option<Region>,
+  // Instead of the image render a mask of what was already clipped in the raw
+  pub sensor_clipping: bool,
+  // The unedited render shown next to the edited one to compare, it's cached
+  // like any other render and this only says where the result goes
+  pub before: bool,
+  // A preview for the browser, same as above only routing
+  pub thumbnail: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -19,19 +73,114 @@ pub struct ImageOutput {
   pub default_ops: imagepipe::PipelineOps,
   pub maxwidth: u32,
   pub maxheight: u32,
+  pub histogram: Histogram,
+  // Width and height of the image as it is in the file, for raws including
+  // the borders that get cropped off
+  pub source_size: Option<(usize, usize)>,
+}
+
+impl ImageOutput {
+  /// Map a point in the render, as fractions of its width and height, to pixel
+  /// coordinates in the original image. The crops are taken to be in the
+  /// orientation the image is shown in, the same way the crop editor does.
+  pub fn source_position(&self, x: f64, y: f64) -> Option<(f64, f64)> {
+    let (width, height) = self.source_size?;
+    let crop = &self.ops.rotatecrop;
+    let x = crop.crop_left as f64 + x * (1.0 - crop.crop_left as f64 - crop.crop_right as f64);
+    let y = crop.crop_top as f64 + y * (1.0 - crop.crop_top as f64 - crop.crop_bottom as f64);
+
+    let transform = &self.ops.transform;
+    let x = if transform.fliph { 1.0 - x } else { x };
+    let y = if transform.flipv { 1.0 - y } else { y };
+    let (x, y) = match transform.rotation {
+      imagepipe::Rotation::Normal => (x, y),
+      imagepipe::Rotation::Rotate90 => (y, 1.0 - x),
+      imagepipe::Rotation::Rotate180 => (1.0 - x, 1.0 - y),
+      imagepipe::Rotation::Rotate270 => (1.0 - y, x),
+    };
+
+    let gofloat = &self.ops.gofloat;
+    let cropped_width = width.saturating_sub(gofloat.crop_left + gofloat.crop_right) as f64;
+    let cropped_height = height.saturating_sub(gofloat.crop_top + gofloat.crop_bottom) as f64;
+    Some((
+      gofloat.crop_left as f64 + x * cropped_width,
+      gofloat.crop_top as f64 + y * cropped_height,
+    ))
+  }
+}
+
+// Size of the image in the file before anything gets done to it
+fn source_size(pipeline: &imagepipe::Pipeline, file: &str) -> Option<(usize, usize)> {
+  match pipeline.globals.image {
+    imagepipe::ImageSource::Raw(ref raw) => Some((raw.width, raw.height)),
+    _ => image::image_dimensions(file).ok().map(|(w, h)| (w as usize, h as usize)),
+  }
+}
+
+/// Why an image couldn't be shown
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+  Unsupported,
+  Io(String),
+  Decode(String),
+  Pipeline(String),
+}
+
+impl fmt::Display for LoadError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      LoadError::Unsupported => write!(f, "not a supported image format"),
+      LoadError::Io(e) => write!(f, "couldn't read the file: {}", e),
+      LoadError::Decode(e) => write!(f, "couldn't decode the image: {}", e),
+      LoadError::Pipeline(e) => write!(f, "processing failed: {}", e),
+    }
+  }
 }
 
+impl std::error::Error for LoadError {}
+
 #[derive(Debug, Clone)]
 pub struct ImageResult {
   pub file: String,
-  pub image: Option<Arc<ImageOutput>>,
+  pub region: Option<Region>,
+  pub sensor_clipping: bool,
+  pub before: bool,
+  pub thumbnail: bool,
+  pub image: Result<Arc<ImageOutput>, LoadError>,
+}
+
+// What the file looked like on disk when it was rendered, so that a file that
+// gets overwritten doesn't keep showing its old contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FileIdentity {
+  size: u64,
+  mtime: Option<std::time::SystemTime>,
+  inode: u64,
+}
+
+impl FileIdentity {
+  fn from_file(file: &str) -> Option<Self> {
+    let meta = std::fs::metadata(file).ok()?;
+    #[cfg(unix)]
+    let inode = std::os::unix::fs::MetadataExt::ino(&meta);
+    #[cfg(not(unix))]
+    let inode = 0;
+    Some(Self {
+      size: meta.len(),
+      mtime: meta.modified().ok(),
+      inode,
+    })
+  }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 struct CacheKey {
   pub file: String,
+  pub identity: Option<FileIdentity>,
   pub level: usize,
   pub ops: Option<imagepipe::PipelineOps>,
+  pub region: Option<Region>,
+  pub sensor_clipping: bool,
 }
 
 const SIZES: [(u32, u32);7] = [
@@ -44,6 +193,9 @@ const SIZES: [(u32, u32);7] = [
   (0, 0),       // Go full size above 5K
 ];
 
+/// Size to ask for to get the smallest level, which is what thumbnails use
+pub const THUMBNAIL_SIZE: (u32, u32) = SIZES[0];
+
 fn find_level(width: u32, height: u32) -> usize {
   for (i,vals) in SIZES.iter().enumerate() {
     if vals.0 >= width && vals.1 >= height {
@@ -58,87 +210,390 @@ impl CacheKey {
     let level = find_level(req.width, req.height);
     CacheKey {
       level,
+      identity: FileIdentity::from_file(&req.file),
       file: req.file,
       ops: req.ops,
+      region: req.region,
+      sensor_clipping: req.sensor_clipping,
     }
   }
 }
 
+// Watcher and export paths can be relative or absolute so compare them the
+// way they're found on disk when they're still there
+fn canonical(file: &str) -> String {
+  std::fs::canonicalize(file).ok()
+    .and_then(|path| path.to_str().map(|p| p.to_string()))
+    .unwrap_or_else(|| file.to_string())
+}
+
 pub struct ImageCache {
   images: MultiCache<CacheKey, ImageOutput>,
-  opbuffers: imagepipe::PipelineCache,
+  // Swapped for an empty one when a file changes, see invalidate()
+  opbuffers: Mutex<Arc<imagepipe::PipelineCache>>,
+  // Files that have gone through the pipeline since opbuffers was last reset
+  rendered: Mutex<HashSet<String>>,
+  // Files we wrote ourselves that the watcher will tell us about, see exported()
+  exported: Mutex<HashSet<String>>,
+  // Images being processed right now by any thread
+  loading: Mutex<HashSet<CacheKey>>,
+  loaded: Condvar,
+  disk: Option<DiskCache>,
 }
 
 impl ImageCache {
   pub fn new() -> ImageCache {
     ImageCache { // For now default to 100MiB for both caches
       images: MultiCache::new(100000000),
-      opbuffers: imagepipe::Pipeline::new_cache(100000000),
+      opbuffers: Mutex::new(Arc::new(imagepipe::Pipeline::new_cache(100000000))),
+      rendered: Mutex::new(HashSet::new()),
+      exported: Mutex::new(HashSet::new()),
+      loading: Mutex::new(HashSet::new()),
+      loaded: Condvar::new(),
+      // Keep up to 2GiB of renders on disk between runs
+      disk: diskcache::default_dir().map(|dir| DiskCache::new(dir.join("renders"), 2000000000)),
     }
   }
 
   pub fn get(&self, req: RequestedImage) -> ImageResult {
     let file = req.file.clone();
+    let region = req.region;
+    let sensor_clipping = req.sensor_clipping;
+    let before = req.before;
+    let thumbnail = req.thumbnail;
     let key = CacheKey::from_request(req);
-    if !self.images.contains_key(&key) {
-      self.load_raw(&key);
+    {
+      // If someone else is already processing this image wait for them
+      let mut loading = self.loading.lock().unwrap();
+      while loading.contains(&key) {
+        loading = self.loaded.wait(loading).unwrap();
+      }
+      if let Some(image) = self.images.get(&key) {
+        return ImageResult { file, region, sensor_clipping, before, thumbnail, image: Ok(image) }
+      }
+      loading.insert(key.clone());
     }
     ImageResult {
       file,
-      image: self.images.get(&key),
+      region,
+      sensor_clipping,
+      before,
+      thumbnail,
+      image: self.load(&key),
     }
   }
 
-  fn load_raw(&self, req: &CacheKey) {
-    let (maxwidth, maxheight) = SIZES[req.level];
+  /// Forget anything that was worked out from a file that changed on disk.
+  /// Renders of the old contents can't be hit anymore as their keys have the
+  /// old file identity and just age out of the cache, but the pipeline's
+  /// intermediate buffers know nothing about the file so start those afresh
+  /// if any of them could have come from it.
+  pub fn invalidate(&self, file: &str) {
+    let file = canonical(file);
+    if self.exported.lock().unwrap().remove(&file) {
+      return
+    }
+    self.forget(&file);
+  }
 
-    log::info!("processing {}", req.file);
+  /// Note a file we just wrote, so that the watcher seeing it show up or
+  /// change doesn't throw away the buffers of everything else
+  pub fn exported(&self, file: &str) {
+    let file = canonical(file);
+    self.forget(&file);
+    self.exported.lock().unwrap().insert(file);
+  }
 
-    let mut pipeline = match imagepipe::Pipeline::new_from_file(&req.file) {
-      Ok(pipe) => pipe,
-      Err(_) => {
-        log::error!("Don't know how to load \"{}\"", req.file);
-        return
+  fn forget(&self, file: &str) {
+    let mut rendered = self.rendered.lock().unwrap();
+    if rendered.remove(file) {
+      log::debug!("invalidating cached buffers after {} changed", file);
+      *self.opbuffers.lock().unwrap() = Arc::new(imagepipe::Pipeline::new_cache(100000000));
+      // Everything else that was in there is gone as well
+      rendered.clear();
+    }
+  }
+
+  /// Process an image into the cache if it's not there or on its way already
+  pub fn prefetch(&self, req: RequestedImage) {
+    let key = CacheKey::from_request(req);
+    let needs_load = {
+      let mut loading = self.loading.lock().unwrap();
+      !self.images.contains_key(&key) && loading.insert(key.clone())
+    };
+    if needs_load {
+      let _ = self.load(&key);
+    }
+  }
+
+  // Process an image that was already marked as loading
+  fn load(&self, key: &CacheKey) -> Result<Arc<ImageOutput>, LoadError> {
+    // A bug in a decoder or an op shouldn't take down the cache thread
+    let result = match panic::catch_unwind(AssertUnwindSafe(|| self.load_raw(key))) {
+      Ok(result) => result,
+      Err(e) => {
+        let msg = e.downcast_ref::<String>().map(|s| s.as_str())
+          .or_else(|| e.downcast_ref::<&str>().copied())
+          .unwrap_or("unknown error");
+        Err(LoadError::Pipeline(msg.to_string()))
       },
     };
+    if let Err(ref e) = result {
+      log::error!("Loading \"{}\" failed: {}", key.file, e);
+    }
+    self.loading.lock().unwrap().remove(key);
+    self.loaded.notify_all();
+    result
+  }
+
+  fn load_raw(&self, req: &CacheKey) -> Result<Arc<ImageOutput>, LoadError> {
+    // Check the file itself first so we can tell a missing or unreadable file
+    // from one we don't know how to decode
+    std::fs::File::open(&req.file).map_err(|e| LoadError::Io(e.to_string()))?;
+    if !directory::is_supported(std::path::Path::new(&req.file)) {
+      return Err(LoadError::Unsupported)
+    }
+
+    if let Some(ref disk) = self.disk {
+      if let Some(value) = disk.get(&req.file, req.level, req.ops.as_ref(), req.region, req.sensor_clipping) {
+        let value = Arc::new(value);
+        self.store(req, value.clone());
+        return Ok(value)
+      }
+    }
+
+    let (mut maxwidth, mut maxheight) = SIZES[req.level];
+    if let Some(region) = req.region {
+      // Shrink the limits along with the image so the region comes out at
+      // the same scale the whole image would
+      let [left, top, right, bottom] = region.fractions();
+      maxwidth = (maxwidth as f64 * (right - left)).ceil() as u32;
+      maxheight = (maxheight as f64 * (bottom - top)).ceil() as u32;
+    }
+
+    log::info!("processing {}", req.file);
+
+    let mut pipeline = imagepipe::Pipeline::new_from_file(&req.file)
+      .map_err(|e| LoadError::Decode(e.to_string()))?;
+    let source_size = source_size(&pipeline, &req.file);
     pipeline.globals.settings.maxwidth = maxwidth as usize;
     pipeline.globals.settings.maxheight = maxheight as usize;
     let default_ops = pipeline.ops.clone();
     if let Some(ref ops) = req.ops {
       pipeline.ops = ops.clone();
     }
-    let decoded = match pipeline.output_8bit(Some(&self.opbuffers)) {
-      Ok(img) => img,
-      Err(_) => {
-        log::error!("Processing for \"{}\" failed", req.file);
-        return
-      },
-    };
-    let imgsize = decoded.width*decoded.height*3;
-    let maxsize = if decoded.width < maxwidth as usize && decoded.height < maxheight as usize {
+    // What the caller asked for, not the crops used for the region
+    let ops = pipeline.ops.clone();
+    if let Some(region) = req.region {
+      region.crop(&mut pipeline.ops);
+    }
+    if req.sensor_clipping {
+      // Put the black level right under the white level so that only what was
+      // already clipped in the raw comes out of the pipeline with any brightness
+      let gofloat = &mut pipeline.ops.gofloat;
+      for i in 0..4 {
+        gofloat.blacklevels[i] = gofloat.whitelevels[i] - 1.0;
+      }
+    }
+    let opbuffers = self.opbuffers.lock().unwrap().clone();
+    self.rendered.lock().unwrap().insert(canonical(&req.file));
+    let decoded = pipeline.output_8bit(Some(&opbuffers))
+      .map_err(|e| LoadError::Pipeline(e.to_string()))?;
+    let full = req.level == SIZES.len() - 1;
+    let maxsize = if full || (decoded.width < maxwidth as usize && decoded.height < maxheight as usize) {
       // This is already native size, there's no point in asking us for larger
       (u32::MAX, u32::MAX)
     } else {
-      (maxwidth, maxheight)
+      SIZES[req.level]
     };
     let value = Arc::new(ImageOutput {
+      source_size,
+      histogram: Histogram::from_image(&decoded),
       image: decoded,
-      ops: pipeline.ops.clone(),
+      ops,
       default_ops,
       maxwidth: maxsize.0,
       maxheight: maxsize.1,
     });
+    if let Some(ref disk) = self.disk {
+      // Full size renders are huge and only wanted when zooming in on a single
+      // image so they're not worth the disk space, tiles of them are fine
+      if !full || req.region.is_some() {
+        disk.put(&req.file, req.level, req.ops.as_ref(), req.region, req.sensor_clipping, &value);
+      }
+    }
+    self.store(req, value.clone());
+    Ok(value)
+  }
+
+  fn store(&self, req: &CacheKey, value: Arc<ImageOutput>) {
+    let imgsize = value.image.width*value.image.height*3;
     if req.ops.is_none() {
       // We have requested an image with default ops so also store in the cache
       // with the ops themselves. Otherwise we would waste time running the whole
       // pipeline just to find an image that we already have.
       let mut newreq = req.clone();
-      newreq.ops = Some(pipeline.ops.clone());
+      newreq.ops = Some(value.ops.clone());
       // This reduces available cache space when in reality the storage is shared
       // thanks to Arc. The old Multicache aliasing stuff would fix that but it
       // seems like too much complexity for a small gain.
       self.images.put_arc(newreq, value.clone(), imgsize);
     }
-    self.images.put_arc(req.clone(), value.clone(), imgsize);
+    self.images.put_arc(req.clone(), value, imgsize);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::backend::testutil::{self, TestDir};
+
+  fn cropped(left: f32, right: f32, top: f32, bottom: f32) -> imagepipe::PipelineOps {
+    let mut ops = testutil::default_ops();
+    let crop = &mut ops.rotatecrop;
+    crop.crop_left = left;
+    crop.crop_right = right;
+    crop.crop_top = top;
+    crop.crop_bottom = bottom;
+    ops
+  }
+
+  fn assert_crops(ops: &imagepipe::PipelineOps, expected: [f32; 4]) {
+    let crop = &ops.rotatecrop;
+    let crops = [crop.crop_left, crop.crop_right, crop.crop_top, crop.crop_bottom];
+    for (val, expected) in crops.iter().zip(expected.iter()) {
+      assert!((val - expected).abs() < 1e-4, "{:?} != {:?}", crops, expected);
+    }
+  }
+
+  fn output(ops: imagepipe::PipelineOps, source_size: Option<(usize, usize)>) -> ImageOutput {
+    let image = SRGBImage { width: 4, height: 3, data: vec![0; 36] };
+    ImageOutput {
+      histogram: Histogram::from_image(&image),
+      image,
+      default_ops: ops.clone(),
+      ops,
+      maxwidth: 640,
+      maxheight: 480,
+      source_size,
+    }
+  }
+
+  #[test]
+  fn region_fractions() {
+    let half = REGION_UNITS / 2;
+    let region = Region { x: half, y: half / 2, width: half, height: half };
+    assert_eq!(region.fractions(), [0.5, 0.25, 1.0, 0.75]);
+  }
+
+  #[test]
+  fn region_crop() {
+    let half = REGION_UNITS / 2;
+    let quarter = REGION_UNITS / 4;
+
+    let mut ops = cropped(0.0, 0.0, 0.0, 0.0);
+    Region { x: half, y: 0, width: half, height: REGION_UNITS }.crop(&mut ops);
+    assert_crops(&ops, [0.5, 0.0, 0.0, 0.0]);
+
+    // The region is of what's left after the user's own crop
+    let mut ops = cropped(0.2, 0.2, 0.0, 0.5);
+    Region { x: quarter, y: quarter, width: half, height: half }.crop(&mut ops);
+    assert_crops(&ops, [0.35, 0.35, 0.125, 0.625]);
+  }
+
+  #[test]
+  fn source_position() {
+    let mut ops = cropped(0.0, 0.0, 0.0, 0.0);
+    ops.transform.fliph = false;
+    ops.transform.flipv = false;
+    ops.transform.rotation = imagepipe::Rotation::Normal;
+    let gofloat = &mut ops.gofloat;
+    gofloat.crop_left = 0;
+    gofloat.crop_right = 0;
+    gofloat.crop_top = 0;
+    gofloat.crop_bottom = 0;
+    assert_eq!(output(ops.clone(), None).source_position(0.5, 0.5), None);
+    assert_eq!(output(ops.clone(), Some((400, 300))).source_position(0.5, 0.5), Some((200.0, 150.0)));
+
+    // Raw borders the pipeline drops still count in the original's pixels
+    let mut bordered = ops.clone();
+    bordered.gofloat.crop_left = 10;
+    bordered.gofloat.crop_right = 10;
+    bordered.gofloat.crop_top = 20;
+    assert_eq!(output(bordered, Some((400, 300))).source_position(0.0, 1.0), Some((10.0, 300.0)));
+
+    let mut trimmed = ops.clone();
+    trimmed.rotatecrop.crop_left = 0.5;
+    assert_eq!(output(trimmed, Some((400, 300))).source_position(0.0, 0.0), Some((200.0, 0.0)));
+
+    let mut rotated = ops;
+    rotated.transform.rotation = imagepipe::Rotation::Rotate90;
+    assert_eq!(output(rotated, Some((400, 300))).source_position(0.25, 0.0), Some((0.0, 225.0)));
+  }
+
+  // No disk cache so that the tests never touch the user's
+  fn memory_cache() -> ImageCache {
+    let mut cache = ImageCache::new();
+    cache.disk = None;
+    cache
+  }
+
+  fn buffers(cache: &ImageCache) -> Arc<imagepipe::PipelineCache> {
+    cache.opbuffers.lock().unwrap().clone()
+  }
+
+  #[test]
+  fn file_identity() {
+    let dir = TestDir::new("cache-identity");
+    let file = dir.file("IMG_0001.CR2", b"first");
+    let file = file.to_str().unwrap();
+    let before = FileIdentity::from_file(file);
+    assert!(before.is_some());
+    assert_eq!(FileIdentity::from_file(file), before);
+    std::fs::write(file, b"rewritten").unwrap();
+    assert_ne!(FileIdentity::from_file(file), before);
+    std::fs::remove_file(file).unwrap();
+    assert_eq!(FileIdentity::from_file(file), None);
+  }
+
+  #[test]
+  fn invalidate_rendered() {
+    let dir = TestDir::new("cache-invalidate");
+    let (a, b) = (dir.file("a.CR2", b"a"), dir.file("b.CR2", b"b"));
+    let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+    let cache = memory_cache();
+    cache.rendered.lock().unwrap().insert(canonical(a));
+
+    // Files that never went through the pipeline can't be in the buffers
+    let old = buffers(&cache);
+    cache.invalidate(b);
+    assert!(Arc::ptr_eq(&old, &buffers(&cache)));
+
+    cache.invalidate(a);
+    assert!(!Arc::ptr_eq(&old, &buffers(&cache)));
+    assert!(cache.rendered.lock().unwrap().is_empty());
+  }
+
+  #[test]
+  fn invalidate_exported() {
+    let dir = TestDir::new("cache-exported");
+    let file = dir.file("a.jpg", b"a");
+    let file = file.to_str().unwrap();
+    let cache = memory_cache();
+    cache.rendered.lock().unwrap().insert(canonical(file));
+
+    // Writing over a file that was shown resets the buffers right away...
+    let old = buffers(&cache);
+    cache.exported(file);
+    let new = buffers(&cache);
+    assert!(!Arc::ptr_eq(&old, &new));
+
+    // ...so when the watcher then reports it nothing more needs doing
+    cache.rendered.lock().unwrap().insert(canonical(file));
+    cache.invalidate(file);
+    assert!(Arc::ptr_eq(&new, &buffers(&cache)));
+    // Only that once, later changes come from someone else
+    cache.invalidate(file);
+    assert!(!Arc::ptr_eq(&new, &buffers(&cache)));
   }
 }
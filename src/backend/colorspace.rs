@@ -0,0 +1,388 @@
+use imagepipe::color_conversions::{apply_srgb_gamma, expand_srgb_gamma};
+
+type Matrix = [[f64; 3]; 3];
+
+// Chromaticities of the whites the spaces are defined against
+const D65: (f64, f64) = (0.3127, 0.3290);
+const D50: (f64, f64) = (0.3457, 0.3585);
+
+// The ICC connection space is always D50
+const D50_XYZ: [f64; 3] = [0.9642, 1.0, 0.8249];
+
+static BRADFORD: Matrix = [
+  [ 0.8951,  0.2664, -0.1614],
+  [-0.7502,  1.7135,  0.0367],
+  [ 0.0389, -0.0685,  1.0296],
+];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Transfer {
+  Srgb,
+  Gamma(f64),
+  // ProPhoto's 1.8 gamma with a short linear segment near black
+  Romm,
+  Linear,
+}
+
+impl Transfer {
+  // Linear light to the encoded value
+  fn encode(&self, v: f32) -> f32 {
+    match *self {
+      Transfer::Srgb => apply_srgb_gamma(v),
+      Transfer::Gamma(gamma) => v.powf(1.0 / gamma as f32),
+      Transfer::Romm => if v < 1.0 / 512.0 { v * 16.0 } else { v.powf(1.0 / 1.8) },
+      Transfer::Linear => v,
+    }
+  }
+
+  // The encoded value back to linear light
+  fn decode(&self, v: f32) -> f32 {
+    match *self {
+      Transfer::Srgb => expand_srgb_gamma(v),
+      Transfer::Gamma(gamma) => v.powf(gamma as f32),
+      Transfer::Romm => if v < 1.0 / 32.0 { v / 16.0 } else { v.powf(1.8) },
+      Transfer::Linear => v,
+    }
+  }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ColorSpace {
+  Srgb,
+  DisplayP3,
+  AdobeRgb,
+  ProPhoto,
+  LinearRec2020,
+}
+
+impl ColorSpace {
+  pub fn name(&self) -> &'static str {
+    match self {
+      ColorSpace::Srgb => "sRGB",
+      ColorSpace::DisplayP3 => "Display P3",
+      ColorSpace::AdobeRgb => "Adobe RGB (1998)",
+      ColorSpace::ProPhoto => "ProPhoto RGB",
+      ColorSpace::LinearRec2020 => "Linear Rec.2020",
+    }
+  }
+
+  /// Spaces that are so wide or so far from perceptual that 8 bits band badly
+  pub fn needs_16bit(&self) -> bool {
+    match self {
+      ColorSpace::ProPhoto | ColorSpace::LinearRec2020 => true,
+      ColorSpace::Srgb | ColorSpace::DisplayP3 | ColorSpace::AdobeRgb => false,
+    }
+  }
+
+  // Red, green and blue primaries followed by the white point
+  fn chromaticities(&self) -> [(f64, f64); 4] {
+    match self {
+      ColorSpace::Srgb => [(0.64, 0.33), (0.30, 0.60), (0.15, 0.06), D65],
+      ColorSpace::DisplayP3 => [(0.680, 0.320), (0.265, 0.690), (0.150, 0.060), D65],
+      ColorSpace::AdobeRgb => [(0.64, 0.33), (0.21, 0.71), (0.15, 0.06), D65],
+      ColorSpace::ProPhoto => [(0.7347, 0.2653), (0.1596, 0.8404), (0.0366, 0.0001), D50],
+      ColorSpace::LinearRec2020 => [(0.708, 0.292), (0.170, 0.797), (0.131, 0.046), D65],
+    }
+  }
+
+  fn transfer(&self) -> Transfer {
+    match self {
+      ColorSpace::Srgb | ColorSpace::DisplayP3 => Transfer::Srgb,
+      ColorSpace::AdobeRgb => Transfer::Gamma(563.0 / 256.0),
+      ColorSpace::ProPhoto => Transfer::Romm,
+      ColorSpace::LinearRec2020 => Transfer::Linear,
+    }
+  }
+
+  // Linear RGB to D50 XYZ, the columns are what ICC calls the colorants
+  fn to_xyz_d50(&self) -> Matrix {
+    let chroma = self.chromaticities();
+    let xyz = |(x, y): (f64, f64)| [x / y, 1.0, (1.0 - x - y) / y];
+    let (r, g, b, white) = (xyz(chroma[0]), xyz(chroma[1]), xyz(chroma[2]), xyz(chroma[3]));
+    let primaries = [
+      [r[0], g[0], b[0]],
+      [r[1], g[1], b[1]],
+      [r[2], g[2], b[2]],
+    ];
+    // Scale the primaries so that full RGB adds up to the white point
+    let scale = apply(&invert(&primaries), white);
+    let mut rgb_to_xyz = primaries;
+    for row in rgb_to_xyz.iter_mut() {
+      for (val, scale) in row.iter_mut().zip(scale.iter()) {
+        *val *= scale;
+      }
+    }
+    multiply(&adaptation(white, D50_XYZ), &rgb_to_xyz)
+  }
+
+  /// Convert gamma encoded 16 bit sRGB, as it comes out of the pipeline, into
+  /// this space and its own transfer curve. Colors that fall outside of the
+  /// space get clipped to it.
+  pub fn convert_from_srgb(&self, data: &mut [u16]) {
+    if *self == ColorSpace::Srgb {
+      return
+    }
+    let matrix = multiply(&invert(&self.to_xyz_d50()), &ColorSpace::Srgb.to_xyz_d50());
+    let matrix: Vec<[f32; 3]> = matrix.iter().map(|row| [row[0] as f32, row[1] as f32, row[2] as f32]).collect();
+    let linear: Vec<f32> = (0..=u16::MAX).map(|v| expand_srgb_gamma(v as f32 / 65535.0)).collect();
+    let transfer = self.transfer();
+    for pix in data.chunks_exact_mut(3) {
+      let rgb = [linear[pix[0] as usize], linear[pix[1] as usize], linear[pix[2] as usize]];
+      for (out, row) in pix.iter_mut().zip(matrix.iter()) {
+        let val = row[0]*rgb[0] + row[1]*rgb[1] + row[2]*rgb[2];
+        *out = (transfer.encode(val.max(0.0).min(1.0)) * 65535.0).round() as u16;
+      }
+    }
+  }
+
+  /// A version 2 matrix/TRC ICC profile describing this space
+  pub fn icc_profile(&self) -> Vec<u8> {
+    let colorants = self.to_xyz_d50();
+    let column = |col: usize| xyz_tag([colorants[0][col], colorants[1][col], colorants[2][col]]);
+    let trc = curve_tag(self.transfer());
+    let tags: Vec<(&[u8; 4], Vec<u8>)> = vec![
+      (b"desc", desc_tag(self.name())),
+      (b"cprt", text_tag("No copyright, use freely")),
+      (b"wtpt", xyz_tag(D50_XYZ)),
+      (b"rXYZ", column(0)),
+      (b"gXYZ", column(1)),
+      (b"bXYZ", column(2)),
+      (b"rTRC", trc.clone()),
+      (b"gTRC", trc.clone()),
+      (b"bTRC", trc),
+    ];
+
+    let mut table = Vec::new();
+    let mut data = Vec::new();
+    let start = 128 + 4 + tags.len() * 12;
+    for (sig, tag) in tags.iter() {
+      table.extend_from_slice(&sig[..]);
+      table.extend_from_slice(&((start + data.len()) as u32).to_be_bytes());
+      table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+      data.extend_from_slice(tag);
+      while data.len() % 4 != 0 {
+        data.push(0);
+      }
+    }
+
+    let size = start + data.len();
+    let mut profile = Vec::with_capacity(size);
+    profile.extend_from_slice(&(size as u32).to_be_bytes());
+    profile.extend_from_slice(&[0; 4]);
+    profile.extend_from_slice(&0x02100000u32.to_be_bytes());
+    profile.extend_from_slice(b"mntrRGB XYZ ");
+    profile.extend_from_slice(&[0; 12]);
+    profile.extend_from_slice(b"acsp");
+    profile.extend_from_slice(&[0; 24]);
+    profile.extend_from_slice(&0u32.to_be_bytes()); // perceptual intent
+    for val in D50_XYZ.iter() {
+      profile.extend_from_slice(&s15fixed16(*val));
+    }
+    profile.resize(128, 0);
+    profile.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+    profile.extend_from_slice(&table);
+    profile.extend_from_slice(&data);
+    profile
+  }
+}
+
+/// Undo the sRGB gamma of an 8 bit value
+pub fn srgb_decode(v: u8) -> f64 {
+  expand_srgb_gamma(v as f32 / 255.0) as f64
+}
+
+/// Linear sRGB to XYZ relative to sRGB's own D65 white
+pub fn srgb_linear_to_xyz(rgb: [f64; 3]) -> [f64; 3] {
+  let d65 = [D65.0 / D65.1, 1.0, (1.0 - D65.0 - D65.1) / D65.1];
+  apply(&multiply(&adaptation(D50_XYZ, d65), &ColorSpace::Srgb.to_xyz_d50()), rgb)
+}
+
+/// Solve `m * x = v` for x, to take a color back through a conversion matrix
+pub fn solve(m: &[[f64; 3]; 3], v: [f64; 3]) -> [f64; 3] {
+  apply(&invert(m), v)
+}
+
+/// CIE Lab, relative to D50 like ICC and most editors use, of an 8 bit sRGB value
+pub fn srgb_to_lab(rgb: [u8; 3]) -> [f64; 3] {
+  let linear = rgb.map(srgb_decode);
+  let xyz = apply(&ColorSpace::Srgb.to_xyz_d50(), linear);
+  let f = |t: f64| {
+    let delta: f64 = 6.0 / 29.0;
+    if t > delta.powi(3) { t.cbrt() } else { t / (3.0 * delta * delta) + 4.0 / 29.0 }
+  };
+  let (fx, fy, fz) = (f(xyz[0] / D50_XYZ[0]), f(xyz[1] / D50_XYZ[1]), f(xyz[2] / D50_XYZ[2]));
+  [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+  let mut out = [[0.0; 3]; 3];
+  for (i, row) in out.iter_mut().enumerate() {
+    for (j, val) in row.iter_mut().enumerate() {
+      *val = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+    }
+  }
+  out
+}
+
+fn apply(m: &Matrix, v: [f64; 3]) -> [f64; 3] {
+  [
+    m[0][0]*v[0] + m[0][1]*v[1] + m[0][2]*v[2],
+    m[1][0]*v[0] + m[1][1]*v[1] + m[1][2]*v[2],
+    m[2][0]*v[0] + m[2][1]*v[1] + m[2][2]*v[2],
+  ]
+}
+
+fn invert(m: &Matrix) -> Matrix {
+  let det = m[0][0] * (m[1][1]*m[2][2] - m[1][2]*m[2][1])
+          - m[0][1] * (m[1][0]*m[2][2] - m[1][2]*m[2][0])
+          + m[0][2] * (m[1][0]*m[2][1] - m[1][1]*m[2][0]);
+  [
+    [
+      (m[1][1]*m[2][2] - m[1][2]*m[2][1]) / det,
+      (m[0][2]*m[2][1] - m[0][1]*m[2][2]) / det,
+      (m[0][1]*m[1][2] - m[0][2]*m[1][1]) / det,
+    ],
+    [
+      (m[1][2]*m[2][0] - m[1][0]*m[2][2]) / det,
+      (m[0][0]*m[2][2] - m[0][2]*m[2][0]) / det,
+      (m[0][2]*m[1][0] - m[0][0]*m[1][2]) / det,
+    ],
+    [
+      (m[1][0]*m[2][1] - m[1][1]*m[2][0]) / det,
+      (m[0][1]*m[2][0] - m[0][0]*m[2][1]) / det,
+      (m[0][0]*m[1][1] - m[0][1]*m[1][0]) / det,
+    ],
+  ]
+}
+
+// Bradford chromatic adaptation between two white points given as XYZ
+fn adaptation(from: [f64; 3], to: [f64; 3]) -> Matrix {
+  let src = apply(&BRADFORD, from);
+  let dst = apply(&BRADFORD, to);
+  let scale = [
+    [dst[0] / src[0], 0.0, 0.0],
+    [0.0, dst[1] / src[1], 0.0],
+    [0.0, 0.0, dst[2] / src[2]],
+  ];
+  multiply(&invert(&BRADFORD), &multiply(&scale, &BRADFORD))
+}
+
+fn s15fixed16(v: f64) -> [u8; 4] {
+  ((v * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn xyz_tag(xyz: [f64; 3]) -> Vec<u8> {
+  let mut tag = b"XYZ \0\0\0\0".to_vec();
+  for val in xyz.iter() {
+    tag.extend_from_slice(&s15fixed16(*val));
+  }
+  tag
+}
+
+fn text_tag(text: &str) -> Vec<u8> {
+  let mut tag = b"text\0\0\0\0".to_vec();
+  tag.extend_from_slice(text.as_bytes());
+  tag.push(0);
+  tag
+}
+
+fn desc_tag(text: &str) -> Vec<u8> {
+  let mut tag = b"desc\0\0\0\0".to_vec();
+  tag.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes());
+  tag.extend_from_slice(text.as_bytes());
+  tag.push(0);
+  // Empty unicode and scriptcode descriptions
+  tag.extend_from_slice(&[0; 8]);
+  tag.extend_from_slice(&[0; 3]);
+  tag.extend_from_slice(&[0; 67]);
+  tag
+}
+
+fn curve_tag(transfer: Transfer) -> Vec<u8> {
+  let mut tag = b"curv\0\0\0\0".to_vec();
+  match transfer {
+    Transfer::Linear => tag.extend_from_slice(&0u32.to_be_bytes()),
+    Transfer::Gamma(g) => {
+      tag.extend_from_slice(&1u32.to_be_bytes());
+      tag.extend_from_slice(&((g * 256.0).round() as u16).to_be_bytes());
+    },
+    // Version 2 profiles have no parametric curves so sample the ones that
+    // aren't a pure gamma
+    Transfer::Srgb | Transfer::Romm => {
+      let points = 1024;
+      tag.extend_from_slice(&(points as u32).to_be_bytes());
+      for i in 0..points {
+        let linear = transfer.decode(i as f32 / (points - 1) as f32);
+        tag.extend_from_slice(&((linear * 65535.0).round() as u16).to_be_bytes());
+      }
+    },
+  }
+  tag
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  static ALL: [ColorSpace; 5] = [
+    ColorSpace::Srgb,
+    ColorSpace::DisplayP3,
+    ColorSpace::AdobeRgb,
+    ColorSpace::ProPhoto,
+    ColorSpace::LinearRec2020,
+  ];
+
+  #[test]
+  fn transfer_round_trip() {
+    for space in ALL.iter() {
+      let transfer = space.transfer();
+      for i in 0..=100 {
+        let v = i as f32 / 100.0;
+        assert!((transfer.decode(transfer.encode(v)) - v).abs() < 1e-4, "{:?} at {}", space, v);
+      }
+    }
+  }
+
+  #[test]
+  fn neutrals_stay_neutral() {
+    for space in ALL.iter() {
+      let mut data = vec![0, 0, 0, 65535, 65535, 65535, 30000, 30000, 30000];
+      space.convert_from_srgb(&mut data);
+      assert_eq!(&data[..6], &[0, 0, 0, 65535, 65535, 65535], "{:?}", space);
+      let grey = &data[6..];
+      assert!(grey.iter().all(|v| (*v as i32 - grey[0] as i32).abs() <= 2), "{:?} {:?}", space, grey);
+    }
+  }
+
+  #[test]
+  fn conversion() {
+    // Pure sRGB red is inside the wider spaces, so not saturated in them
+    let mut data = vec![65535, 0, 0];
+    ColorSpace::DisplayP3.convert_from_srgb(&mut data);
+    assert!(data[0] < 65535 && data[1] > 0 && data[2] > 0, "{:?}", data);
+    // Linear keeps a mid grey at its light intensity instead of its sRGB code
+    let mut data = vec![32768, 32768, 32768];
+    ColorSpace::LinearRec2020.convert_from_srgb(&mut data);
+    let expected = (expand_srgb_gamma(32768.0 / 65535.0) * 65535.0).round() as i32;
+    assert!((data[0] as i32 - expected).abs() <= 2, "{:?} instead of {}", data, expected);
+  }
+
+  #[test]
+  fn icc_profile() {
+    for space in ALL.iter() {
+      let profile = space.icc_profile();
+      let size = u32::from_be_bytes([profile[0], profile[1], profile[2], profile[3]]) as usize;
+      assert_eq!(size, profile.len());
+      assert_eq!(&profile[36..40], b"acsp");
+      assert_eq!(&profile[12..20], b"mntrRGB ");
+      // The white of every space maps onto the connection space's D50
+      let white = apply(&space.to_xyz_d50(), [1.0, 1.0, 1.0]);
+      for (val, expected) in white.iter().zip(D50_XYZ.iter()) {
+        assert!((val - expected).abs() < 1e-3, "{:?} {:?}", space, white);
+      }
+    }
+    // Each space gets its own curve
+    assert_ne!(ColorSpace::Srgb.icc_profile(), ColorSpace::DisplayP3.icc_profile());
+    assert_ne!(curve_tag(ColorSpace::AdobeRgb.transfer()), curve_tag(ColorSpace::Srgb.transfer()));
+  }
+}
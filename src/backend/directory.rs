@@ -0,0 +1,335 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Sender, TryRecvError};
+use std::time::SystemTime;
+use crate::backend::metadata::{self, ImageMetadata};
+
+// Extensions of the raw formats rawloader can decode
+static RAW_EXTENSIONS: [&str; 25] = [
+  "3fr", "ari", "arw", "cr2", "crw", "dcr", "dcs", "dng", "erf", "iiq", "kdc",
+  "mef", "mos", "mrw", "nef", "nrw", "orf", "pef", "raf", "rw2", "rwl", "sr2",
+  "srf", "srw", "x3f",
+];
+
+// Extensions of the other image formats that go through the image crate
+static IMAGE_EXTENSIONS: [&str; 11] = [
+  "jpg", "jpeg", "png", "tif", "tiff", "webp", "gif", "bmp", "pnm", "ppm", "tga",
+];
+
+static JPEG_EXTENSIONS: [&str; 2] = ["jpg", "jpeg"];
+
+/// Which of the files we can open to show when browsing
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Filter {
+  Everything,
+  Raw,
+  Jpeg,
+}
+
+impl Filter {
+  pub const ALL: [(&'static str, Filter); 3] = [
+    ("Everything", Filter::Everything),
+    ("Raw only", Filter::Raw),
+    ("JPEG only", Filter::Jpeg),
+  ];
+
+  pub fn matches(&self, path: &Path) -> bool {
+    match self {
+      Filter::Everything => is_supported(path),
+      Filter::Raw => is_raw(path),
+      Filter::Jpeg => extension(path).map(|e| JPEG_EXTENSIONS.contains(&e.as_str())).unwrap_or(false),
+    }
+  }
+
+  /// Every spelling of the extensions that match. The file navigator compares
+  /// them exactly and files come with any mix of cases, like .CR2 or .Jpg.
+  pub fn extensions(&self) -> Vec<String> {
+    let extensions: Vec<&str> = match self {
+      Filter::Everything => RAW_EXTENSIONS.iter().chain(IMAGE_EXTENSIONS.iter()).cloned().collect(),
+      Filter::Raw => RAW_EXTENSIONS.to_vec(),
+      Filter::Jpeg => JPEG_EXTENSIONS.to_vec(),
+    };
+    extensions.iter()
+      .flat_map(|e| case_variants(e))
+      .collect()
+  }
+}
+
+// All the ways of writing a word in upper and lower case letters
+fn case_variants(word: &str) -> Vec<String> {
+  let mut variants = vec![String::new()];
+  for c in word.chars() {
+    let (lower, upper) = (c.to_ascii_lowercase(), c.to_ascii_uppercase());
+    variants = variants.into_iter().flat_map(|v| {
+      if lower == upper {
+        vec![format!("{}{}", v, c)]
+      } else {
+        vec![format!("{}{}", v, lower), format!("{}{}", v, upper)]
+      }
+    }).collect();
+  }
+  variants
+}
+
+/// What order to browse and step through a directory in
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Sort {
+  Name,
+  CaptureTime,
+  Modified,
+  Size,
+  Camera,
+}
+
+impl Sort {
+  pub const ALL: [(&'static str, Sort); 5] = [
+    ("Name", Sort::Name),
+    ("Capture time", Sort::CaptureTime),
+    ("Modified", Sort::Modified),
+    ("Size", Sort::Size),
+    ("Camera", Sort::Camera),
+  ];
+
+  /// Whether the order depends on what's in the files
+  pub fn uses_metadata(&self) -> bool {
+    match self {
+      Sort::CaptureTime | Sort::Camera => true,
+      Sort::Name | Sort::Modified | Sort::Size => false,
+    }
+  }
+}
+
+type MetadataEntries = HashMap<PathBuf, (Option<SystemTime>, ImageMetadata)>;
+
+/// The metadata sorting needs, kept around so changing the order or coming
+/// back to a directory doesn't read every file again. Reading means opening
+/// every file so it's done on a thread of its own, which calls `on_read`
+/// whenever it catches up so the files can be sorted again. Files that
+/// changed since get read again.
+pub struct MetadataCache {
+  entries: Arc<Mutex<MetadataEntries>>,
+  // Files already sent to be read, by their modification time then
+  requested: HashSet<(PathBuf, Option<SystemTime>)>,
+  requests: Sender<(PathBuf, Option<SystemTime>)>,
+}
+
+impl MetadataCache {
+  pub fn new<F>(on_read: F) -> Self
+  where F: Fn() + Send + 'static {
+    let entries = Arc::new(Mutex::new(HashMap::new()));
+    let (requests, rx) = std::sync::mpsc::channel::<(PathBuf, Option<SystemTime>)>();
+    let worker_entries = entries.clone();
+    // This goes away on its own once the cache is dropped
+    std::thread::spawn(move || {
+      let mut next = rx.recv().ok();
+      while let Some((path, mtime)) = next {
+        let meta = path.to_str().map(metadata::read).unwrap_or_default();
+        worker_entries.lock().unwrap().insert(path, (mtime, meta));
+        next = match rx.try_recv() {
+          Ok(req) => Some(req),
+          Err(TryRecvError::Empty) => {
+            on_read();
+            rx.recv().ok()
+          },
+          Err(TryRecvError::Disconnected) => None,
+        };
+      }
+    });
+    Self {
+      entries,
+      requested: HashSet::new(),
+      requests,
+    }
+  }
+
+  /// The metadata of a file if it has been read already, otherwise it gets
+  /// asked for
+  pub fn get(&mut self, path: &Path) -> Option<ImageMetadata> {
+    let mtime = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    if let Some((cached, meta)) = self.entries.lock().unwrap().get(path) {
+      if *cached == mtime {
+        return Some(meta.clone())
+      }
+    }
+    if self.requested.insert((path.to_path_buf(), mtime)) {
+      let _ = self.requests.send((path.to_path_buf(), mtime));
+    }
+    None
+  }
+}
+
+fn file_name(path: &Path) -> String {
+  path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+// Split a name into runs of digits and everything else
+fn chunks(name: &str) -> Vec<&str> {
+  let mut chunks = Vec::new();
+  let mut start = 0;
+  let mut digits = None;
+  for (pos, c) in name.char_indices() {
+    let isdigit = c.is_ascii_digit();
+    if digits.is_some() && digits != Some(isdigit) {
+      chunks.push(&name[start..pos]);
+      start = pos;
+    }
+    digits = Some(isdigit);
+  }
+  if start < name.len() {
+    chunks.push(&name[start..]);
+  }
+  chunks
+}
+
+/// Compare names the way people count, so IMG_2 goes before IMG_10
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+  let (chunksa, chunksb) = (chunks(a), chunks(b));
+  for (ca, cb) in chunksa.iter().zip(chunksb.iter()) {
+    let isnum = |c: &str| c.bytes().all(|b| b.is_ascii_digit());
+    let order = if isnum(ca) && isnum(cb) {
+      // Compare by value without parsing so any length works, then have
+      // fewer leading zeros go first
+      let (ta, tb) = (ca.trim_start_matches('0'), cb.trim_start_matches('0'));
+      ta.len().cmp(&tb.len()).then_with(|| ta.cmp(tb)).then_with(|| ca.len().cmp(&cb.len()))
+    } else {
+      ca.to_lowercase().cmp(&cb.to_lowercase()).then_with(|| ca.cmp(cb))
+    };
+    if order != Ordering::Equal {
+      return order
+    }
+  }
+  chunksa.len().cmp(&chunksb.len())
+}
+
+// Sort by a key worked out once per file, with ties going by name
+fn sort_by_key<K: Ord>(images: &mut Vec<PathBuf>, mut key: impl FnMut(&Path) -> K) {
+  let mut keyed: Vec<(K, String, PathBuf)> = images.drain(..)
+    .map(|path| (key(&path), file_name(&path), path))
+    .collect();
+  keyed.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| natural_cmp(&a.1, &b.1)));
+  images.extend(keyed.into_iter().map(|(_, _, path)| path));
+}
+
+// A key that puts the files that don't have the value after all the others
+fn last_if_none<T>(val: Option<T>) -> (bool, Option<T>) {
+  (val.is_none(), val)
+}
+
+/// Put images in browsing order. Files missing what's being sorted by, like
+/// JPEGs without EXIF or ones whose metadata hasn't been read yet, go at the
+/// end.
+pub fn sort(images: &mut Vec<PathBuf>, order: Sort, cache: &mut MetadataCache) {
+  match order {
+    Sort::Name => sort_by_key(images, |_| ()),
+    Sort::CaptureTime => sort_by_key(images, |path| {
+      last_if_none(cache.get(path).and_then(|meta| meta.capture_time))
+    }),
+    Sort::Modified => sort_by_key(images, |path| {
+      last_if_none(std::fs::metadata(path).and_then(|m| m.modified()).ok())
+    }),
+    Sort::Size => sort_by_key(images, |path| last_if_none(std::fs::metadata(path).map(|m| m.len()).ok())),
+    Sort::Camera => sort_by_key(images, |path| {
+      let meta = cache.get(path).unwrap_or_default();
+      (last_if_none(meta.camera().map(|c| c.to_lowercase())), last_if_none(meta.capture_time))
+    }),
+  }
+}
+
+fn extension(path: &Path) -> Option<String> {
+  path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase())
+}
+
+pub fn is_raw(path: &Path) -> bool {
+  extension(path).map(|e| RAW_EXTENSIONS.contains(&e.as_str())).unwrap_or(false)
+}
+
+pub fn is_supported(path: &Path) -> bool {
+  extension(path).map(|e| {
+    RAW_EXTENSIONS.contains(&e.as_str()) || IMAGE_EXTENSIONS.contains(&e.as_str())
+  }).unwrap_or(false)
+}
+
+/// All the images we know how to open in a directory, sorted by name
+pub fn list_images(dir: &Path) -> Vec<PathBuf> {
+  list_filtered(dir, Filter::Everything)
+}
+
+/// The images in a directory that pass a filter, sorted by name
+pub fn list_filtered(dir: &Path, filter: Filter) -> Vec<PathBuf> {
+  let entries = match std::fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(e) => {
+      log::error!("Error reading directory \"{}\": {}", dir.display(), e);
+      return Vec::new()
+    },
+  };
+  let mut images: Vec<PathBuf> = entries
+    .filter_map(|entry| entry.ok())
+    .map(|entry| entry.path())
+    .filter(|path| path.is_file() && filter.matches(path))
+    .collect();
+  images.sort();
+  images
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sorted(names: &[&str]) -> Vec<String> {
+    let mut names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+    names.sort_by(|a, b| natural_cmp(a, b));
+    names
+  }
+
+  #[test]
+  fn numbers_by_value() {
+    assert_eq!(natural_cmp("IMG_2.CR2", "IMG_10.CR2"), Ordering::Less);
+    assert_eq!(natural_cmp("IMG_10.CR2", "IMG_2.CR2"), Ordering::Greater);
+    assert_eq!(sorted(&["a10", "a2", "a1", "a20"]), vec!["a1", "a2", "a10", "a20"]);
+  }
+
+  #[test]
+  fn longer_than_integers() {
+    assert_eq!(natural_cmp("x99999999999999999999999", "x100000000000000000000000"), Ordering::Less);
+  }
+
+  #[test]
+  fn leading_zeros() {
+    assert_eq!(natural_cmp("IMG_0010", "IMG_9"), Ordering::Greater);
+    // Same value, fewer zeros first so the order is still total
+    assert_eq!(natural_cmp("IMG_01", "IMG_1"), Ordering::Greater);
+    assert_eq!(natural_cmp("IMG_1", "IMG_01"), Ordering::Less);
+  }
+
+  #[test]
+  fn case_and_prefixes() {
+    assert_eq!(natural_cmp("apple", "Banana"), Ordering::Less);
+    // Only differing in case still has an order
+    assert_eq!(natural_cmp("IMG", "img"), Ordering::Less);
+    assert_eq!(natural_cmp("IMG", "IMG_1"), Ordering::Less);
+    assert_eq!(natural_cmp("", "a"), Ordering::Less);
+    assert_eq!(natural_cmp("same", "same"), Ordering::Equal);
+  }
+
+  #[test]
+  fn extensions_any_case() {
+    let extensions = Filter::Everything.extensions();
+    for ext in ["cr2", "CR2", "Cr2", "cR2", "Jpg", "jPeG", "x3f", "X3F", "TIFF"].iter() {
+      assert!(extensions.iter().any(|e| e == ext), "{} is missing", ext);
+    }
+    assert_eq!(case_variants("nef").len(), 8);
+    assert_eq!(case_variants("rw2"), vec!["rw2", "rW2", "Rw2", "RW2"]);
+    assert!(!Filter::Jpeg.extensions().iter().any(|e| e.eq_ignore_ascii_case("cr2")));
+  }
+
+  #[test]
+  fn mixed_runs() {
+    assert_eq!(
+      sorted(&["DSC_1a", "DSC_1", "dsc_1b", "DSC_01a", "1", "a"]),
+      vec!["1", "a", "DSC_1", "DSC_1a", "DSC_01a", "dsc_1b"],
+    );
+  }
+}
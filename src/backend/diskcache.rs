@@ -0,0 +1,359 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::backend::cache::{ImageOutput, Region};
+use crate::backend::histogram::Histogram;
+
+// Bump this whenever the entry layout changes, old entries then just miss
+const MAGIC: &[u8; 8] = b"CHIMPRC2";
+// Once over the limit evict down to this fraction of it so we're not
+// deleting files on every single write
+const EVICT_TO: f64 = 0.9;
+
+// FNV-1a, we want something stable across runs and builds which the std
+// hasher doesn't promise
+fn fnv64(data: &[u8]) -> u64 {
+  let mut hash: u64 = 0xcbf29ce484222325;
+  for byte in data {
+    hash ^= *byte as u64;
+    hash = hash.wrapping_mul(0x100000001b3);
+  }
+  hash
+}
+
+/// Where cache files go by default, following the XDG base directory spec
+pub fn default_dir() -> Option<PathBuf> {
+  let base = std::env::var_os("XDG_CACHE_HOME")
+    .filter(|dir| !dir.is_empty())
+    .map(PathBuf::from)
+    .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+  Some(base.join("chimper"))
+}
+
+struct Reader<'a> {
+  data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+  fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+    if self.data.len() < len {
+      return None
+    }
+    let (out, rest) = self.data.split_at(len);
+    self.data = rest;
+    Some(out)
+  }
+
+  fn u32(&mut self) -> Option<u32> {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(self.bytes(4)?);
+    Some(u32::from_le_bytes(buf))
+  }
+
+  fn block(&mut self) -> Option<&'a [u8]> {
+    let len = self.u32()? as usize;
+    self.bytes(len)
+  }
+}
+
+fn push_block(out: &mut Vec<u8>, data: &[u8]) {
+  out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+  out.extend_from_slice(data);
+}
+
+/// A second tier for `ImageCache` that keeps rendered images on disk between
+/// runs. Entries are keyed by the file's path, size and modification time as
+/// well as the size level, the ops and the region rendered, so any change to
+/// the file or the edits just misses. Least recently used entries get evicted
+/// once the cache grows over its size limit.
+pub struct DiskCache {
+  dir: PathBuf,
+  limit: u64,
+  // Total size of the entries, only worked out the first time it's needed
+  size: Mutex<Option<u64>>,
+}
+
+impl DiskCache {
+  pub fn new(dir: PathBuf, limit: u64) -> Self {
+    Self {
+      dir,
+      limit,
+      size: Mutex::new(None),
+    }
+  }
+
+  // Everything that identifies an entry, stored in it to catch hash collisions
+  fn key(file: &str, level: usize, ops: Option<&imagepipe::PipelineOps>, region: Option<Region>, sensor_clipping: bool) -> Option<Vec<u8>> {
+    let meta = fs::metadata(file).ok()?;
+    let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    let ops = match ops {
+      Some(ops) => serde_yaml::to_string(ops).ok()?,
+      None => "default".to_string(),
+    };
+    let mut key = format!("{}\0{}\0{}.{:09}\0{}\0{:016x}",
+      file, meta.len(), mtime.as_secs(), mtime.subsec_nanos(), level, fnv64(ops.as_bytes())
+    );
+    if let Some(r) = region {
+      key.push_str(&format!("\0{},{},{},{}", r.x, r.y, r.width, r.height));
+    }
+    if sensor_clipping {
+      key.push_str("\0sensor");
+    }
+    Some(key.into_bytes())
+  }
+
+  fn path(&self, key: &[u8]) -> PathBuf {
+    self.dir.join(format!("{:016x}.cache", fnv64(key)))
+  }
+
+  fn decode(key: &[u8], data: &[u8]) -> Option<ImageOutput> {
+    if data.len() < MAGIC.len() + 8 || &data[..MAGIC.len()] != MAGIC {
+      return None
+    }
+    let (body, checksum) = data.split_at(data.len() - 8);
+    let mut buf = [0; 8];
+    buf.copy_from_slice(checksum);
+    if fnv64(body) != u64::from_le_bytes(buf) {
+      return None
+    }
+
+    let mut reader = Reader { data: &body[MAGIC.len()..] };
+    if reader.block()? != key {
+      return None
+    }
+    let width = reader.u32()? as usize;
+    let height = reader.u32()? as usize;
+    let maxwidth = reader.u32()?;
+    let maxheight = reader.u32()?;
+    let source_size = match (reader.u32()?, reader.u32()?) {
+      (0, 0) => None,
+      (w, h) => Some((w as usize, h as usize)),
+    };
+    let ops = serde_yaml::from_slice(reader.block()?).ok()?;
+    let default_ops = serde_yaml::from_slice(reader.block()?).ok()?;
+    let pixels = reader.bytes(width * height * 3)?;
+    let image = imagepipe::SRGBImage {
+      width,
+      height,
+      data: pixels.to_vec(),
+    };
+    Some(ImageOutput {
+      // Cheap enough to just work out again
+      histogram: Histogram::from_image(&image),
+      source_size,
+      image,
+      ops,
+      default_ops,
+      maxwidth,
+      maxheight,
+    })
+  }
+
+  fn encode(key: &[u8], value: &ImageOutput) -> Option<Vec<u8>> {
+    let image = &value.image;
+    let mut out = Vec::with_capacity(image.data.len() + 64 * 1024);
+    out.extend_from_slice(MAGIC);
+    push_block(&mut out, key);
+    let (srcwidth, srcheight) = value.source_size.unwrap_or((0, 0));
+    for val in [image.width as u32, image.height as u32, value.maxwidth, value.maxheight,
+                srcwidth as u32, srcheight as u32].iter() {
+      out.extend_from_slice(&val.to_le_bytes());
+    }
+    push_block(&mut out, serde_yaml::to_string(&value.ops).ok()?.as_bytes());
+    push_block(&mut out, serde_yaml::to_string(&value.default_ops).ok()?.as_bytes());
+    out.extend_from_slice(&image.data);
+    let checksum = fnv64(&out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    Some(out)
+  }
+
+  pub fn get(&self, file: &str, level: usize, ops: Option<&imagepipe::PipelineOps>, region: Option<Region>, sensor_clipping: bool) -> Option<ImageOutput> {
+    let key = Self::key(file, level, ops, region, sensor_clipping)?;
+    let path = self.path(&key);
+    let mut data = Vec::new();
+    fs::File::open(&path).ok()?.read_to_end(&mut data).ok()?;
+    match Self::decode(&key, &data) {
+      Some(value) => {
+        // The modification time of the entry is what the eviction goes by
+        if let Ok(f) = fs::File::options().write(true).open(&path) {
+          let _ = f.set_modified(SystemTime::now());
+        }
+        log::debug!("loaded {} from the disk cache", file);
+        Some(value)
+      },
+      None => {
+        log::warn!("Discarding corrupt disk cache entry \"{}\"", path.display());
+        self.remove(&path);
+        None
+      },
+    }
+  }
+
+  pub fn put(&self, file: &str, level: usize, ops: Option<&imagepipe::PipelineOps>, region: Option<Region>, sensor_clipping: bool, value: &ImageOutput) {
+    let key = if let Some(key) = Self::key(file, level, ops, region, sensor_clipping) { key } else { return };
+    let data = if let Some(data) = Self::encode(&key, value) { data } else { return };
+    if data.len() as u64 > self.limit {
+      return
+    }
+    let path = self.path(&key);
+    let previous = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+    if let Err(e) = self.write(&path, &data) {
+      log::error!("Error writing disk cache entry \"{}\": {}", path.display(), e);
+      return
+    }
+
+    let mut size = self.size.lock().unwrap();
+    let total = match *size {
+      Some(total) => total - previous.min(total) + data.len() as u64,
+      None => self.scan().iter().map(|e| e.1).sum(),
+    };
+    *size = Some(if total > self.limit { self.evict() } else { total });
+  }
+
+  fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+    fs::create_dir_all(&self.dir)?;
+    // Write to a temporary file and rename it so a crash never leaves a
+    // truncated entry behind, the checksum catches anything else
+    let tmppath = path.with_extension("tmp");
+    let mut f = fs::File::create(&tmppath)?;
+    f.write_all(data)?;
+    drop(f);
+    fs::rename(&tmppath, path)
+  }
+
+  fn remove(&self, path: &Path) {
+    let len = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    if fs::remove_file(path).is_ok() {
+      if let Some(ref mut size) = *self.size.lock().unwrap() {
+        *size -= len.min(*size);
+      }
+    }
+  }
+
+  // All the entries with their size and last use
+  fn scan(&self) -> Vec<(PathBuf, u64, SystemTime)> {
+    let entries = match fs::read_dir(&self.dir) {
+      Ok(entries) => entries,
+      Err(_) => return Vec::new(),
+    };
+    entries.filter_map(|entry| entry.ok())
+      .map(|entry| entry.path())
+      .filter(|path| path.extension().map(|e| e == "cache").unwrap_or(false))
+      .filter_map(|path| {
+        let meta = fs::metadata(&path).ok()?;
+        Some((path, meta.len(), meta.modified().unwrap_or(UNIX_EPOCH)))
+      })
+      .collect()
+  }
+
+  // Delete the least recently used entries until we're well under the limit,
+  // returning the new total size
+  fn evict(&self) -> u64 {
+    let mut entries = self.scan();
+    entries.sort_by_key(|e| e.2);
+    let mut total: u64 = entries.iter().map(|e| e.1).sum();
+    let target = (self.limit as f64 * EVICT_TO) as u64;
+    for (path, len, _) in entries {
+      if total <= target {
+        break
+      }
+      if fs::remove_file(&path).is_ok() {
+        log::debug!("evicted {} from the disk cache", path.display());
+        total -= len;
+      }
+    }
+    total
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::backend::testutil::{self, TestDir};
+
+  // A file for the keys to point to and a render to store for it
+  fn sample(dir: &TestDir) -> (String, ImageOutput) {
+    let file = dir.file("sample.CR2", b"raw data").to_str().unwrap().to_string();
+    let ops = testutil::default_ops();
+    let image = imagepipe::SRGBImage {
+      width: 4,
+      height: 3,
+      data: (0..36).collect(),
+    };
+    (file, ImageOutput {
+      histogram: Histogram::from_image(&image),
+      source_size: Some((4, 3)),
+      image,
+      ops: ops.clone(),
+      default_ops: ops,
+      maxwidth: 640,
+      maxheight: 480,
+    })
+  }
+
+  fn key(file: &str, level: usize) -> Vec<u8> {
+    DiskCache::key(file, level, None, None, false).unwrap()
+  }
+
+  #[test]
+  fn round_trip() {
+    let dir = TestDir::new("diskcache-round-trip");
+    let (file, value) = sample(&dir);
+    let key = key(&file, 0);
+    let decoded = DiskCache::decode(&key, &DiskCache::encode(&key, &value).unwrap()).unwrap();
+    assert_eq!((decoded.image.width, decoded.image.height), (4, 3));
+    assert_eq!(decoded.image.data, value.image.data);
+    assert_eq!(decoded.histogram, value.histogram);
+    assert_eq!(decoded.source_size, Some((4, 3)));
+    assert_eq!((decoded.maxwidth, decoded.maxheight), (640, 480));
+    assert_eq!(decoded.ops, value.ops);
+    assert_eq!(decoded.default_ops, value.default_ops);
+  }
+
+  #[test]
+  fn corrupted_entries() {
+    let dir = TestDir::new("diskcache-corrupted");
+    let (file, value) = sample(&dir);
+    let key = key(&file, 0);
+    let mut data = DiskCache::encode(&key, &value).unwrap();
+    // Cut short like a crash in the middle of a write would
+    assert!(DiskCache::decode(&key, &data[..data.len() - 1]).is_none());
+    // Flip a bit in the pixels, which nothing but the checksum looks at
+    let pos = data.len() - 8 - 1;
+    data[pos] ^= 1;
+    assert!(DiskCache::decode(&key, &data).is_none());
+  }
+
+  #[test]
+  fn key_mismatch() {
+    let dir = TestDir::new("diskcache-key-mismatch");
+    let (file, value) = sample(&dir);
+    let (key, other) = (key(&file, 0), key(&file, 1));
+    assert_ne!(key, other);
+    let data = DiskCache::encode(&key, &value).unwrap();
+    assert!(DiskCache::decode(&other, &data).is_none());
+  }
+
+  #[test]
+  fn evicts_below_target() {
+    let dir = TestDir::new("diskcache-evict");
+    let (file, value) = sample(&dir);
+    let entry = DiskCache::encode(&key(&file, 0), &value).unwrap().len() as u64;
+    // Room for three and a half entries so the fourth goes over the limit
+    let limit = entry * 7 / 2;
+    let cache = DiskCache::new(dir.path().join("renders"), limit);
+    for level in 0..3 {
+      cache.put(&file, level, None, None, false, &value);
+    }
+    assert_eq!(cache.scan().len(), 3);
+    cache.put(&file, 3, None, None, false, &value);
+
+    let entries = cache.scan();
+    let total: u64 = entries.iter().map(|e| e.1).sum();
+    assert!(total <= (limit as f64 * EVICT_TO) as u64);
+    assert_eq!(entries.len(), 3);
+    assert_eq!(*cache.size.lock().unwrap(), Some(total));
+  }
+}
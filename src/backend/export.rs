@@ -1,49 +1,668 @@
-use std::fs::File;
-use std::io::BufWriter;
-use image::ColorType;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Cursor, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use image::{ColorType, ImageEncoder};
+use img_parts::{Bytes, ImageEXIF, ImageICC};
+use crate::backend::metadata;
+use crate::backend::metadata::ExportMetadata;
+pub use crate::backend::metadata::MetadataPolicy;
+pub use crate::backend::colorspace::ColorSpace;
+
+// Signatures that start the APP1 segments in a JPEG
+const JPEG_EXIF_HEADER: &[u8] = b"Exif\0\0";
+const JPEG_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ExportFormat {
+  Jpeg,
+  Png,
+  Tiff,
+  WebP,
+}
+
+impl ExportFormat {
+  pub fn extension(&self) -> &'static str {
+    match self {
+      ExportFormat::Jpeg => "jpg",
+      ExportFormat::Png  => "png",
+      ExportFormat::Tiff => "tif",
+      ExportFormat::WebP => "webp",
+    }
+  }
+
+  pub fn supports_16bit(&self) -> bool {
+    match self {
+      ExportFormat::Png | ExportFormat::Tiff => true,
+      ExportFormat::Jpeg | ExportFormat::WebP => false,
+    }
+  }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BitDepth {
+  Eight,
+  Sixteen,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ChromaSubsampling {
+  Yuv444,
+  Yuv422,
+  Yuv420,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ExportSettings {
+  pub format: ExportFormat,
+  // Only used by JPEG, PNG and WebP are always lossless
+  pub quality: u8,
+  // Formats that can't do 16 bits silently fall back to 8
+  pub bitdepth: BitDepth,
+  // Only used by JPEG
+  pub subsampling: ChromaSubsampling,
+  pub metadata: MetadataPolicy,
+  // The wide spaces that need 16 bits fall back to sRGB without them
+  pub colorspace: ColorSpace,
+}
+
+impl Default for ExportSettings {
+  fn default() -> Self {
+    Self {
+      format: ExportFormat::Jpeg,
+      quality: 90,
+      bitdepth: BitDepth::Eight,
+      subsampling: ChromaSubsampling::Yuv444,
+      metadata: MetadataPolicy::Keep,
+      colorspace: ColorSpace::Srgb,
+    }
+  }
+}
+
+impl ExportSettings {
+  pub fn is_16bit(&self) -> bool {
+    self.bitdepth == BitDepth::Sixteen && self.format.supports_16bit()
+  }
+
+  pub fn colorspace(&self) -> ColorSpace {
+    if self.colorspace.needs_16bit() && !self.is_16bit() {
+      ColorSpace::Srgb
+    } else {
+      self.colorspace
+    }
+  }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CollisionPolicy {
+  Overwrite,
+  Skip,
+  Suffix,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExportDestination {
+  // Write next to the source file when not set
+  pub directory: Option<String>,
+  // Output file name without the extension, which comes from the format.
+  // Supports {stem}, {ext}, {date}, {camera} and {seq}
+  pub template: String,
+  pub collision: CollisionPolicy,
+}
+
+impl Default for ExportDestination {
+  fn default() -> Self {
+    Self {
+      directory: None,
+      template: "{stem}.{ext}".to_string(),
+      collision: CollisionPolicy::Suffix,
+    }
+  }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct RequestedExport {
+  // Identifies the job in the status updates
+  pub id: u64,
   pub file: String,
   pub ops: Option<imagepipe::PipelineOps>,
+  pub settings: ExportSettings,
+  pub destination: ExportDestination,
+  // Used for the {seq} token
+  pub seq: u32,
 }
 
-pub fn export_file(req: &RequestedExport) {
-  let outfile = format!("{}.jpg", req.file);
-  log::info!("Exporting {} into {}", req.file, outfile);
+#[derive(Debug)]
+pub enum ExportError {
+  Unsupported(String),
+  Processing(String),
+  Encoding(String),
+  Exists(PathBuf),
+  Io(std::io::Error),
+}
 
-  let mut pipeline = match imagepipe::Pipeline::new_from_file(&req.file) {
-    Ok(pipe) => pipe,
-    Err(_) => {
-      log::error!("Don't know how to load \"{}\"", req.file);
-      return
-    },
+impl fmt::Display for ExportError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ExportError::Unsupported(file) => write!(f, "don't know how to load \"{}\"", file),
+      ExportError::Processing(e) => write!(f, "processing failed: {}", e),
+      ExportError::Encoding(e) => write!(f, "encoding failed: {}", e),
+      ExportError::Exists(path) => write!(f, "\"{}\" already exists", path.display()),
+      ExportError::Io(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl std::error::Error for ExportError {}
+
+impl From<std::io::Error> for ExportError {
+  fn from(e: std::io::Error) -> Self {
+    ExportError::Io(e)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExportState {
+  Queued,
+  Running,
+  Done(PathBuf),
+  Failed(String),
+  // The output was already there and the collision policy said to leave it
+  Skipped(PathBuf),
+  Cancelled,
+}
+
+impl ExportState {
+  pub fn is_finished(&self) -> bool {
+    match self {
+      ExportState::Queued | ExportState::Running => false,
+      ExportState::Done(_) | ExportState::Failed(_) | ExportState::Skipped(_) |
+      ExportState::Cancelled => true,
+    }
+  }
+}
+
+/// Sent back from the export thread whenever a job changes state
+#[derive(Debug, Clone)]
+pub struct ExportStatus {
+  pub id: u64,
+  pub file: String,
+  pub state: ExportState,
+}
+
+/// Ids of queued jobs that should be dropped instead of exported
+pub type CancelledExports = Arc<Mutex<HashSet<u64>>>;
+
+// Values coming from the file or its metadata shouldn't be able to add
+// directories to the output path
+fn sanitize(value: &str) -> String {
+  value.chars().map(|c| if c == '/' || c == '\\' { '_' } else { c }).collect()
+}
+
+/// Expand the tokens in the file name template for a given source file
+pub fn render_template(template: &str, file: &str, seq: u32) -> String {
+  let path = Path::new(file);
+  let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+  let ext = path.extension().and_then(|s| s.to_str()).unwrap_or("");
+
+  let mut out = template.replace("{stem}", &sanitize(stem))
+    .replace("{ext}", &sanitize(ext))
+    .replace("{seq}", &format!("{:04}", seq));
+  if out.contains("{date}") || out.contains("{camera}") {
+    let meta = metadata::read(file);
+    let date = meta.capture_time.or_else(|| {
+      std::fs::metadata(file).and_then(|m| m.modified()).ok()
+        .map(metadata::Timestamp::from_system_time)
+    }).map(|t| t.date_string()).unwrap_or_else(|| "unknown".to_string());
+    let camera = meta.camera().unwrap_or_else(|| "unknown".to_string());
+    out = out.replace("{date}", &sanitize(&date)).replace("{camera}", &sanitize(&camera));
+  }
+  out
+}
+
+// Create a file only if nothing is there yet, so that workers racing for the
+// same name can't both get it
+fn create_new(path: &Path) -> std::io::Result<Option<File>> {
+  match OpenOptions::new().write(true).create_new(true).open(path) {
+    Ok(f) => Ok(Some(f)),
+    Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(None),
+    Err(e) => Err(e),
+  }
+}
+
+/// A file an export is being written into. Unless `finish` is called it gets
+/// removed again, so a failed export doesn't leave an empty or cut short file
+/// behind, and a file being overwritten is only replaced once the new one is
+/// complete.
+pub struct OutputFile {
+  pub path: PathBuf,
+  pub file: File,
+  // Where the data goes first when it's replacing an existing file
+  partial: Option<PathBuf>,
+  finished: bool,
+}
+
+impl OutputFile {
+  fn new(path: PathBuf, file: File) -> Self {
+    Self { path, file, partial: None, finished: false }
+  }
+
+  fn replacing(path: PathBuf) -> std::io::Result<Self> {
+    let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let mut num = 0;
+    loop {
+      let partial = path.with_file_name(format!(".{}.{}.part", name, num));
+      if let Some(file) = create_new(&partial)? {
+        return Ok(Self { path, file, partial: Some(partial), finished: false })
+      }
+      num += 1;
+    }
+  }
+
+  /// Keep the file now that it has been completely written
+  pub fn finish(mut self) -> std::io::Result<PathBuf> {
+    if let Some(ref partial) = self.partial {
+      std::fs::rename(partial, &self.path)?;
+    }
+    self.finished = true;
+    Ok(self.path.clone())
+  }
+}
+
+impl Drop for OutputFile {
+  fn drop(&mut self) {
+    if !self.finished {
+      let written = self.partial.as_ref().unwrap_or(&self.path);
+      if let Err(e) = std::fs::remove_file(written) {
+        log::warn!("Couldn't remove unfinished export {}: {}", written.display(), e);
+      }
+    }
+  }
+}
+
+/// Work out where an export should be written to and open it, failing with
+/// `ExportError::Exists` if it should be skipped because the file is there
+pub fn output_path(req: &RequestedExport) -> Result<OutputFile, ExportError> {
+  let dest = &req.destination;
+  let directory = match dest.directory {
+    Some(ref dir) => PathBuf::from(dir),
+    None => Path::new(&req.file).parent().map(|p| p.to_path_buf()).unwrap_or_default(),
   };
-  if let Some(ref ops) = req.ops {
-    pipeline.ops = ops.clone();
+  std::fs::create_dir_all(&directory)?;
+  let name = render_template(&dest.template, &req.file, req.seq);
+  let ext = req.settings.format.extension();
+  let path = directory.join(format!("{}.{}", name, ext));
+  if let Some(f) = create_new(&path)? {
+    return Ok(OutputFile::new(path, f))
   }
-  let decoded = match pipeline.output_8bit(None) {
-    Ok(img) => img,
-    Err(_) => {
-      log::error!("Processing for \"{}\" failed", req.file);
-      return
+  match dest.collision {
+    CollisionPolicy::Overwrite => Ok(OutputFile::replacing(path)?),
+    CollisionPolicy::Skip => Err(ExportError::Exists(path)),
+    CollisionPolicy::Suffix => {
+      let mut num = 1;
+      loop {
+        let path = directory.join(format!("{}-{}.{}", name, num, ext));
+        if let Some(f) = create_new(&path)? {
+          return Ok(OutputFile::new(path, f))
+        }
+        num += 1;
+      }
     },
-  };
-  let uf = match File::create(&outfile) {
-    Ok(val) => val,
-    Err(e) => {
-      log::error!("Error creating output file: {}", e);
-      return;
+  }
+}
+
+fn encode_jpeg<W: Write>(w: W, settings: &ExportSettings, meta: &ExportMetadata, data: &[u8], width: usize, height: usize) -> Result<(), ExportError> {
+  if width > u16::MAX as usize || height > u16::MAX as usize {
+    return Err(ExportError::Encoding(format!("{}x{} is too large for a jpg", width, height)))
+  }
+  let mut encoder = jpeg_encoder::Encoder::new(w, settings.quality.max(1).min(100));
+  encoder.set_sampling_factor(match settings.subsampling {
+    ChromaSubsampling::Yuv444 => jpeg_encoder::SamplingFactor::R_4_4_4,
+    ChromaSubsampling::Yuv422 => jpeg_encoder::SamplingFactor::R_4_2_2,
+    ChromaSubsampling::Yuv420 => jpeg_encoder::SamplingFactor::R_4_2_0,
+  });
+  // A single APP1 segment has to fit everything so metadata that's too large
+  // gets dropped instead of failing the whole export
+  let segments = meta.exif_blob().map(|blob| [JPEG_EXIF_HEADER, &blob[..]].concat()).into_iter()
+    .chain(meta.xmp.as_ref().map(|xmp| [JPEG_XMP_HEADER, &xmp[..]].concat()));
+  for segment in segments {
+    if let Err(e) = encoder.add_app_segment(1, &segment) {
+      log::warn!("Not writing metadata into the jpg: {}", e);
     }
-  };
-  let mut f = BufWriter::new(uf);
-  let mut jpg_encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut f, 90);
-  match jpg_encoder.encode(&decoded.data, decoded.width as u32, decoded.height as u32, ColorType::Rgb8) {
-    Ok(_) => {},
-    Err(_) => {
-      log::error!("Error encoding jpg for \"{}\"", req.file);
-      return
+  }
+  if let Some(ref icc) = meta.icc {
+    encoder.add_icc_profile(icc).map_err(|e| ExportError::Encoding(e.to_string()))?;
+  }
+  encoder.encode(data, width as u16, height as u16, jpeg_encoder::ColorType::Rgb)
+    .map_err(|e| ExportError::Encoding(e.to_string()))
+}
+
+// The image crate's TIFF encoder can't add tags so write the whole file with
+// the EXIF writer, uncompressed and as a single strip
+fn encode_tiff(meta: &ExportMetadata, data: &[u8], width: u32, height: u32, bits: u16) -> Result<Vec<u8>, exif::Error> {
+  let field = |tag, value| exif::Field { tag, ifd_num: exif::In::PRIMARY, value };
+  let mut fields = vec![
+    field(exif::Tag::ImageWidth, exif::Value::Long(vec![width])),
+    field(exif::Tag::ImageLength, exif::Value::Long(vec![height])),
+    field(exif::Tag::BitsPerSample, exif::Value::Short(vec![bits; 3])),
+    field(exif::Tag::Compression, exif::Value::Short(vec![1])),
+    field(exif::Tag::PhotometricInterpretation, exif::Value::Short(vec![2])),
+    field(exif::Tag::SamplesPerPixel, exif::Value::Short(vec![3])),
+    field(exif::Tag::RowsPerStrip, exif::Value::Long(vec![height])),
+    field(exif::Tag::PlanarConfiguration, exif::Value::Short(vec![1])),
+  ];
+  if let Some(ref xmp) = meta.xmp {
+    fields.push(field(exif::Tag(exif::Context::Tiff, 700), exif::Value::Byte(xmp.clone())));
+  }
+  if let Some(ref icc) = meta.icc {
+    fields.push(field(exif::Tag(exif::Context::Tiff, 34675), exif::Value::Undefined(icc.clone(), 0)));
+  }
+  let strips = [data];
+  let mut writer = exif::experimental::Writer::new();
+  for field in fields.iter().chain(meta.fields.iter()) {
+    writer.push_field(field);
+  }
+  writer.set_strips(&strips, exif::In::PRIMARY);
+  // 16 bit samples come in native endian
+  let mut buffer = Cursor::new(Vec::new());
+  writer.write(&mut buffer, cfg!(target_endian = "little"))?;
+  Ok(buffer.into_inner())
+}
+
+// PNG keeps XMP in an uncompressed iTXt chunk with a well known keyword
+fn png_xmp_chunk(xmp: &[u8]) -> img_parts::png::PngChunk {
+  let mut contents = b"XML:com.adobe.xmp\0\0\0\0\0".to_vec();
+  contents.extend_from_slice(xmp);
+  img_parts::png::PngChunk::new(*b"iTXt", Bytes::from(contents))
+}
+
+fn encode_image<W: Write>(mut w: W, settings: &ExportSettings, meta: &ExportMetadata, data: &[u8], width: usize, height: usize, color: ColorType) -> Result<(), ExportError> {
+  let (width, height) = (width as u32, height as u32);
+  let encoding = |e: image::ImageError| ExportError::Encoding(e.to_string());
+  let parsing = |e: img_parts::Error| ExportError::Encoding(e.to_string());
+  let exif = meta.exif_blob().map(Bytes::from);
+  let icc = meta.icc.clone().map(Bytes::from);
+  match settings.format {
+    ExportFormat::Jpeg => unreachable!(),
+    ExportFormat::Png => {
+      // Encode to memory first so the metadata chunks can be added after
+      let mut buffer = Vec::new();
+      image::codecs::png::PngEncoder::new_with_quality(
+        &mut buffer,
+        image::codecs::png::CompressionType::Best,
+        image::codecs::png::FilterType::Adaptive,
+      ).write_image(data, width, height, color).map_err(encoding)?;
+      let mut png = img_parts::png::Png::from_bytes(Bytes::from(buffer)).map_err(parsing)?;
+      png.set_exif(exif);
+      png.set_icc_profile(icc);
+      if let Some(ref xmp) = meta.xmp {
+        let pos = png.chunks().len() - 1;
+        png.chunks_mut().insert(pos, png_xmp_chunk(xmp));
+      }
+      png.encoder().write_to(w)?;
+    },
+    ExportFormat::Tiff => {
+      let bits = if color == ColorType::Rgb16 { 16 } else { 8 };
+      let tiff = encode_tiff(meta, data, width, height, bits)
+        .map_err(|e| ExportError::Encoding(e.to_string()))?;
+      w.write_all(&tiff)?;
     },
+    ExportFormat::WebP => {
+      let mut buffer = Vec::new();
+      image::codecs::webp::WebPEncoder::new_lossless(&mut buffer)
+        .write_image(data, width, height, color).map_err(encoding)?;
+      let mut webp = img_parts::webp::WebP::from_bytes(Bytes::from(buffer)).map_err(parsing)?;
+      webp.set_exif(exif);
+      webp.set_icc_profile(icc);
+      webp.encoder().write_to(w)?;
+    },
+  }
+  Ok(())
+}
+
+pub fn export_file(req: &RequestedExport) -> Result<PathBuf, ExportError> {
+  let settings = &req.settings;
+  let outfile = output_path(req)?;
+  log::info!("Exporting {} into {}", req.file, outfile.path.display());
+
+  let mut pipeline = imagepipe::Pipeline::new_from_file(&req.file)
+    .map_err(|_| ExportError::Unsupported(req.file.clone()))?;
+  if let Some(ref ops) = req.ops {
+    pipeline.ops = ops.clone();
+  }
+
+  let colorspace = settings.colorspace();
+  let meta = metadata::for_export(&req.file, settings.metadata, colorspace);
+  let mut f = BufWriter::new(&outfile.file);
+
+  if settings.is_16bit() {
+    let mut decoded = pipeline.output_16bit(None).map_err(ExportError::Processing)?;
+    colorspace.convert_from_srgb(&mut decoded.data);
+    let data: Vec<u8> = decoded.data.iter().flat_map(|v| v.to_ne_bytes()).collect();
+    encode_image(&mut f, settings, &meta, &data, decoded.width, decoded.height, ColorType::Rgb16)?;
+  } else {
+    let (data, width, height) = if colorspace == ColorSpace::Srgb {
+      let decoded = pipeline.output_8bit(None).map_err(ExportError::Processing)?;
+      (decoded.data, decoded.width, decoded.height)
+    } else {
+      // Convert from 16 bits so there's a single rounding step down to 8
+      let mut decoded = pipeline.output_16bit(None).map_err(ExportError::Processing)?;
+      colorspace.convert_from_srgb(&mut decoded.data);
+      let data = decoded.data.iter().map(|v| ((*v as u32 * 255 + 32767) / 65535) as u8).collect();
+      (data, decoded.width, decoded.height)
+    };
+    if settings.format == ExportFormat::Jpeg {
+      encode_jpeg(&mut f, settings, &meta, &data, width, height)?;
+    } else {
+      encode_image(&mut f, settings, &meta, &data, width, height, ColorType::Rgb8)?;
+    }
+  }
+  f.flush()?;
+  drop(f);
+
+  let outfile = outfile.finish()?;
+  log::info!("Finished exporting {}", outfile.display());
+  Ok(outfile)
+}
+
+struct QueueState {
+  jobs: VecDeque<RequestedExport>,
+  closed: bool,
+}
+
+/// Queue of exports shared between a pool of worker threads
+pub struct ExportQueue {
+  state: Mutex<QueueState>,
+  available: Condvar,
+}
+
+impl ExportQueue {
+  pub fn new() -> Arc<Self> {
+    Arc::new(Self {
+      state: Mutex::new(QueueState {
+        jobs: VecDeque::new(),
+        closed: false,
+      }),
+      available: Condvar::new(),
+    })
+  }
+
+  pub fn push(&self, req: RequestedExport) {
+    self.state.lock().unwrap().jobs.push_back(req);
+    self.available.notify_one();
+  }
+
+  /// No more jobs are coming, workers exit once the queue is drained
+  pub fn close(&self) {
+    self.state.lock().unwrap().closed = true;
+    self.available.notify_all();
+  }
+
+  // Block until there's a job to do or there will never be one
+  fn pop(&self) -> Option<RequestedExport> {
+    let mut state = self.state.lock().unwrap();
+    loop {
+      if let Some(req) = state.jobs.pop_front() {
+        return Some(req)
+      }
+      if state.closed {
+        return None
+      }
+      state = self.available.wait(state).unwrap();
+    }
+  }
+}
+
+/// How many exports to run at once. The pipeline is already multithreaded and
+/// each job holds a full size image in memory so keep this small.
+pub fn default_workers() -> usize {
+  let cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+  (cpus / 4).max(1).min(4)
+}
+
+/// Start a pool of threads that run the exports in the queue, reporting each
+/// job's progress through `report`
+pub fn spawn_workers<F>(
+  queue: &Arc<ExportQueue>,
+  nworkers: usize,
+  cancelled: CancelledExports,
+  report: F,
+) -> Vec<std::thread::JoinHandle<()>>
+where F: Fn(ExportStatus) + Clone + Send + 'static {
+  (0..nworkers).map(|_| {
+    let queue = queue.clone();
+    let cancelled = cancelled.clone();
+    let report = report.clone();
+    std::thread::spawn(move || {
+      let send = |req: &RequestedExport, state| report(ExportStatus {
+        id: req.id,
+        file: req.file.clone(),
+        state,
+      });
+      while let Some(req) = queue.pop() {
+        if cancelled.lock().unwrap().remove(&req.id) {
+          send(&req, ExportState::Cancelled);
+          continue
+        }
+        send(&req, ExportState::Running);
+        let state = match export_file(&req) {
+          Ok(path) => ExportState::Done(path),
+          Err(ExportError::Exists(path)) => {
+            log::info!("Skipping \"{}\", {} already exists", req.file, path.display());
+            ExportState::Skipped(path)
+          },
+          Err(e) => {
+            log::error!("Exporting \"{}\" failed: {}", req.file, e);
+            ExportState::Failed(e.to_string())
+          },
+        };
+        // A cancel that came in after the job started is too late to matter
+        cancelled.lock().unwrap().remove(&req.id);
+        send(&req, state);
+      }
+    })
+  }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::backend::testutil::TestDir;
+
+  fn request(file: &Path, outdir: &Path, template: &str, collision: CollisionPolicy) -> RequestedExport {
+    RequestedExport {
+      id: 0,
+      file: file.to_str().unwrap().to_string(),
+      ops: None,
+      settings: ExportSettings::default(),
+      destination: ExportDestination {
+        directory: Some(outdir.to_str().unwrap().to_string()),
+        template: template.to_string(),
+        collision,
+      },
+      seq: 7,
+    }
+  }
+
+  #[test]
+  fn template_tokens() {
+    assert_eq!(render_template("{stem}.{ext}", "/photos/IMG_0001.CR2", 1), "IMG_0001.CR2");
+    assert_eq!(render_template("{stem}-{seq}", "IMG_0001.CR2", 42), "IMG_0001-0042");
+    assert_eq!(render_template("trip", "/photos/IMG_0001.CR2", 1), "trip");
+    assert_eq!(render_template("{stem}", "no_extension", 1), "no_extension");
+  }
+
+  #[test]
+  fn template_metadata() {
+    let dir = TestDir::new("template-metadata");
+    // Without EXIF the date comes from the file and the camera is unknown
+    let file = dir.file("notes.txt", b"no metadata here");
+    let file = file.to_str().unwrap();
+    let modified = metadata::Timestamp::from_system_time(std::fs::metadata(file).unwrap().modified().unwrap());
+    assert_eq!(render_template("{date}_{camera}", file, 1), format!("{}_unknown", modified.date_string()));
+  }
+
+  #[test]
+  fn template_no_directories() {
+    assert_eq!(sanitize("a/b\\c"), "a_b_c");
+    assert!(!render_template("{stem}", "/photos/a\\b.CR2", 1).contains('\\'));
+  }
+
+  #[test]
+  fn wide_spaces_need_16bit() {
+    let mut settings = ExportSettings { colorspace: ColorSpace::ProPhoto, ..Default::default() };
+    assert_eq!(settings.colorspace(), ColorSpace::Srgb);
+    settings.format = ExportFormat::Tiff;
+    settings.bitdepth = BitDepth::Sixteen;
+    assert_eq!(settings.colorspace(), ColorSpace::ProPhoto);
+    settings.colorspace = ColorSpace::AdobeRgb;
+    settings.format = ExportFormat::Jpeg;
+    assert_eq!(settings.colorspace(), ColorSpace::AdobeRgb);
+  }
+
+  #[test]
+  fn collisions() {
+    let dir = TestDir::new("export-collisions");
+    let source = dir.path().join("IMG_0001.CR2");
+    let taken = dir.file("IMG_0001.jpg", b"old");
+
+    let req = |collision| request(&source, dir.path(), "{stem}", collision);
+    // Each export reserves its name until it's dropped so the next one moves on
+    let first = output_path(&req(CollisionPolicy::Suffix)).unwrap();
+    assert_eq!(first.path, dir.path().join("IMG_0001-1.jpg"));
+    let second = output_path(&req(CollisionPolicy::Suffix)).unwrap();
+    assert_eq!(second.path, dir.path().join("IMG_0001-2.jpg"));
+    match output_path(&req(CollisionPolicy::Skip)) {
+      Err(ExportError::Exists(path)) => assert_eq!(path, taken),
+      _ => panic!("expected the export to be skipped"),
+    }
+
+    // Overwriting only replaces the file once the new one is finished
+    let replacing = output_path(&req(CollisionPolicy::Overwrite)).unwrap();
+    assert_eq!(replacing.path, taken);
+    (&replacing.file).write_all(b"new").unwrap();
+    assert_eq!(std::fs::read(&taken).unwrap(), b"old");
+    assert_eq!(replacing.finish().unwrap(), taken);
+    assert_eq!(std::fs::read(&taken).unwrap(), b"new");
+
+    // Unfinished exports don't leave anything behind
+    drop(first);
+    drop(second);
+    let mut left: Vec<PathBuf> = std::fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().path()).collect();
+    left.sort();
+    assert_eq!(left, vec![taken]);
+  }
+
+  #[test]
+  fn creates_directory() {
+    let dir = TestDir::new("export-directory");
+    let outdir = dir.path().join("nested").join("out");
+    let req = request(&dir.path().join("a.CR2"), &outdir, "{stem}-{seq}", CollisionPolicy::Suffix);
+    let out = output_path(&req).unwrap();
+    assert_eq!(out.path, outdir.join("a-0007.jpg"));
+    assert_eq!(out.finish().unwrap(), outdir.join("a-0007.jpg"));
+    assert!(outdir.join("a-0007.jpg").exists());
   }
-  log::info!("Finished exporting {}", outfile);
 }
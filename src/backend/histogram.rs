@@ -0,0 +1,80 @@
+extern crate imagepipe;
+use self::imagepipe::SRGBImage;
+
+/// Number of pixels at each 8 bit value of a render, per channel and for the
+/// luminance
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+  pub red: Vec<u32>,
+  pub green: Vec<u32>,
+  pub blue: Vec<u32>,
+  pub luma: Vec<u32>,
+}
+
+impl Histogram {
+  pub fn from_image(image: &SRGBImage) -> Self {
+    let mut red = vec![0; 256];
+    let mut green = vec![0; 256];
+    let mut blue = vec![0; 256];
+    let mut luma = vec![0; 256];
+    for pixel in image.data.chunks_exact(3) {
+      let (r, g, b) = (pixel[0] as usize, pixel[1] as usize, pixel[2] as usize);
+      red[r] += 1;
+      green[g] += 1;
+      blue[b] += 1;
+      // Rec.709 weights in 8 bit fixed point, they add up to 256
+      luma[(54 * r + 183 * g + 19 * b) >> 8] += 1;
+    }
+    Self { red, green, blue, luma }
+  }
+
+  /// The highest count outside of the two ends, where clipped pixels pile up
+  /// and would otherwise flatten everything else
+  pub fn peak(&self) -> u32 {
+    [&self.red, &self.green, &self.blue, &self.luma].iter()
+      .flat_map(|channel| channel[1..255].iter())
+      .copied()
+      .max()
+      .unwrap_or(0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn image(pixels: &[[u8; 3]]) -> SRGBImage {
+    SRGBImage {
+      width: pixels.len(),
+      height: 1,
+      data: pixels.iter().flatten().copied().collect(),
+    }
+  }
+
+  #[test]
+  fn binning() {
+    let histogram = Histogram::from_image(&image(&[[255, 255, 255], [255, 0, 0], [10, 20, 30], [10, 20, 30]]));
+    assert_eq!(histogram.red[255], 2);
+    assert_eq!(histogram.red[10], 2);
+    assert_eq!(histogram.green[0], 1);
+    assert_eq!(histogram.green[20], 2);
+    assert_eq!(histogram.blue[30], 2);
+    // White stays white and pure red only has its share of the luminance
+    assert_eq!(histogram.luma[255], 1);
+    assert_eq!(histogram.luma[(54 * 255) >> 8], 1);
+    for channel in [&histogram.red, &histogram.green, &histogram.blue, &histogram.luma].iter() {
+      assert_eq!(channel.len(), 256);
+      assert_eq!(channel.iter().sum::<u32>(), 4);
+    }
+  }
+
+  #[test]
+  fn peak_ignores_clipping() {
+    let mut pixels = vec![[0, 0, 0]; 10];
+    pixels.extend(vec![[255, 255, 255]; 10]);
+    pixels.extend(vec![[100, 120, 140]; 3]);
+    pixels.push([100, 0, 0]);
+    assert_eq!(Histogram::from_image(&image(&pixels)).peak(), 4);
+    assert_eq!(Histogram::from_image(&image(&[[0, 0, 0], [255, 255, 255]])).peak(), 0);
+  }
+}
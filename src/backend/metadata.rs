@@ -0,0 +1,365 @@
+extern crate exif;
+use std::fs::File;
+use crate::backend::colorspace::ColorSpace;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// How much of the start of a TIFF based file to look for the metadata in
+const HEAD_SIZE: u64 = 256 * 1024;
+
+// Raws that are TIFF inside but start with a signature of their own, and the
+// TIFF header to parse them with
+static TIFF_VARIANTS: [(&[u8], &[u8]); 4] = [
+  (b"IIRO", b"II*\0"), // Olympus ORF
+  (b"IIRS", b"II*\0"), // Older Olympus ORF
+  (b"MMOR", b"MM\0*"), // Big endian Olympus ORF
+  (b"IIU\0", b"II*\0"), // Panasonic RW2 and Leica RWL
+];
+
+const RAF_MAGIC: &[u8] = b"FUJIFILMCCD-RAW ";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+  pub year: u16,
+  pub month: u8,
+  pub day: u8,
+  pub hour: u8,
+  pub minute: u8,
+  pub second: u8,
+}
+
+impl Timestamp {
+  /// Convert a system time to a UTC timestamp
+  pub fn from_system_time(time: SystemTime) -> Self {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) as i64;
+    let days = secs.div_euclid(86400);
+    let daysecs = secs.rem_euclid(86400);
+    // Days since epoch to civil date from Howard Hinnant's date algorithms
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe/1460 + doe/36524 - doe/146096) / 365;
+    let doy = doe - (365*yoe + yoe/4 - yoe/100);
+    let mp = (5*doy + 2)/153;
+    let day = doy - (153*mp+2)/5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    Self {
+      year: year as u16,
+      month: month as u8,
+      day: day as u8,
+      hour: (daysecs / 3600) as u8,
+      minute: ((daysecs % 3600) / 60) as u8,
+      second: (daysecs % 60) as u8,
+    }
+  }
+
+  pub fn date_string(&self) -> String {
+    format!("{:04}-{:02}-{:02}", self.year, self.month, self.day)
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ImageMetadata {
+  pub make: Option<String>,
+  pub model: Option<String>,
+  pub capture_time: Option<Timestamp>,
+}
+
+impl ImageMetadata {
+  /// A name for the camera, the model usually already includes the make
+  pub fn camera(&self) -> Option<String> {
+    match (&self.make, &self.model) {
+      (Some(make), Some(model)) => {
+        let firstword = make.split_whitespace().next().unwrap_or("");
+        if model.to_lowercase().starts_with(&firstword.to_lowercase()) {
+          Some(model.clone())
+        } else {
+          Some(format!("{} {}", make, model))
+        }
+      },
+      (None, Some(model)) => Some(model.clone()),
+      (Some(make), None) => Some(make.clone()),
+      (None, None) => None,
+    }
+  }
+}
+
+pub fn read_exif(file: &str) -> Option<exif::Exif> {
+  let mut reader = BufReader::new(File::open(file).ok()?);
+  let mut head = Vec::new();
+  (&mut reader).take(HEAD_SIZE).read_to_end(&mut head).ok()?;
+  if head.starts_with(RAF_MAGIC) {
+    return read_raf_exif(&mut reader, &head)
+  }
+  if let Some((_, tiff)) = TIFF_VARIANTS.iter().find(|(magic, _)| head.starts_with(magic)) {
+    head[..4].copy_from_slice(tiff);
+  }
+  if head.starts_with(b"II*\0") || head.starts_with(b"MM\0*") {
+    // TIFF based raws keep their metadata near the start of the file, going
+    // through all of a large raw to look for it elsewhere isn't worth it
+    return exif::Reader::new().read_raw(head).ok()
+  }
+  reader.seek(SeekFrom::Start(0)).ok()?;
+  exif::Reader::new().read_from_container(&mut reader).ok()
+}
+
+// Fuji RAFs aren't TIFF based but carry a JPEG preview with the EXIF in it,
+// found through an offset and length in their header
+fn read_raf_exif<R: Read + Seek>(reader: &mut R, head: &[u8]) -> Option<exif::Exif> {
+  let field = |pos: usize| {
+    head.get(pos..pos+4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as u64)
+  };
+  let (offset, length) = (field(84)?, field(88)?);
+  reader.seek(SeekFrom::Start(offset)).ok()?;
+  // The EXIF is at the start of the JPEG so the image itself isn't needed
+  let mut jpeg = Vec::new();
+  reader.take(length.min(HEAD_SIZE)).read_to_end(&mut jpeg).ok()?;
+  exif::Reader::new().read_from_container(&mut Cursor::new(jpeg)).ok()
+}
+
+fn ascii_field(exif: &exif::Exif, tag: exif::Tag) -> Option<String> {
+  let field = exif.get_field(tag, exif::In::PRIMARY)?;
+  if let exif::Value::Ascii(ref vals) = field.value {
+    let val = String::from_utf8_lossy(vals.first()?);
+    let val = val.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    if !val.is_empty() {
+      return Some(val.to_string())
+    }
+  }
+  None
+}
+
+fn time_field(exif: &exif::Exif, tag: exif::Tag) -> Option<Timestamp> {
+  let field = exif.get_field(tag, exif::In::PRIMARY)?;
+  if let exif::Value::Ascii(ref vals) = field.value {
+    let time = exif::DateTime::from_ascii(vals.first()?).ok()?;
+    return Some(Timestamp {
+      year: time.year,
+      month: time.month,
+      day: time.day,
+      hour: time.hour,
+      minute: time.minute,
+      second: time.second,
+    })
+  }
+  None
+}
+
+pub fn read(file: &str) -> ImageMetadata {
+  let exif = match read_exif(file) {
+    Some(exif) => exif,
+    None => return ImageMetadata::default(),
+  };
+  ImageMetadata {
+    make: ascii_field(&exif, exif::Tag::Make),
+    model: ascii_field(&exif, exif::Tag::Model),
+    capture_time: time_field(&exif, exif::Tag::DateTimeOriginal)
+      .or_else(|| time_field(&exif, exif::Tag::DateTime)),
+  }
+}
+
+/// How much of the source's metadata to carry over into an export
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MetadataPolicy {
+  Keep,
+  // Everything but the GPS position
+  StripGps,
+  // Also drop anything that identifies the photographer or their gear
+  StripPersonal,
+  StripAll,
+}
+
+// The IFD0 tags worth copying, the others describe the layout of the source
+// file and would be wrong for the export
+static TIFF_TAGS: [exif::Tag; 6] = [
+  exif::Tag::ImageDescription,
+  exif::Tag::Make,
+  exif::Tag::Model,
+  exif::Tag::DateTime,
+  exif::Tag::Artist,
+  exif::Tag::Copyright,
+];
+
+// Exif IFD tags that are either about the source encoding or that we can't
+// relocate safely like the maker notes which are full of absolute offsets
+static SKIPPED_EXIF_TAGS: [exif::Tag; 6] = [
+  exif::Tag::MakerNote,
+  exif::Tag::PixelXDimension,
+  exif::Tag::PixelYDimension,
+  exif::Tag::ComponentsConfiguration,
+  exif::Tag::CompressedBitsPerPixel,
+  exif::Tag::ColorSpace,
+];
+
+static PERSONAL_TAGS: [exif::Tag; 6] = [
+  exif::Tag::Artist,
+  exif::Tag::CameraOwnerName,
+  exif::Tag::BodySerialNumber,
+  exif::Tag::LensSerialNumber,
+  exif::Tag::ImageUniqueID,
+  exif::Tag::UserComment,
+];
+
+// Where TIFF based files keep their XMP packet
+const XMP_TAG: exif::Tag = exif::Tag(exif::Context::Tiff, 700);
+
+/// Metadata to embed into an exported image
+#[derive(Debug, Clone, Default)]
+pub struct ExportMetadata {
+  pub fields: Vec<exif::Field>,
+  pub xmp: Option<Vec<u8>>,
+  // Always there whatever the policy as it's needed to show the image right
+  pub icc: Option<Vec<u8>>,
+}
+
+impl ExportMetadata {
+  /// The EXIF fields as a standalone TIFF structure, the way JPEG, PNG and
+  /// WebP embed them
+  pub fn exif_blob(&self) -> Option<Vec<u8>> {
+    if self.fields.is_empty() {
+      return None
+    }
+    let mut writer = exif::experimental::Writer::new();
+    for field in &self.fields {
+      writer.push_field(field);
+    }
+    let mut buffer = std::io::Cursor::new(Vec::new());
+    match writer.write(&mut buffer, false) {
+      Ok(_) => Some(buffer.into_inner()),
+      Err(e) => {
+        log::error!("Error writing EXIF: {}", e);
+        None
+      },
+    }
+  }
+}
+
+fn keep_field(field: &exif::Field, policy: MetadataPolicy) -> bool {
+  if field.ifd_num != exif::In::PRIMARY {
+    return false
+  }
+  if let exif::Value::Unknown(..) = field.value {
+    return false
+  }
+  if policy == MetadataPolicy::StripPersonal && PERSONAL_TAGS.contains(&field.tag) {
+    return false
+  }
+  match field.tag.context() {
+    exif::Context::Tiff => TIFF_TAGS.contains(&field.tag),
+    exif::Context::Exif => !SKIPPED_EXIF_TAGS.contains(&field.tag),
+    exif::Context::Gps => policy == MetadataPolicy::Keep,
+    exif::Context::Interop => false,
+  }
+}
+
+/// Set the orientation in an XMP packet to normal, written in either of the
+/// ways RDF allows. Packets that aren't text give None as we can't tell what
+/// they say. A value that never gets closed stops the resetting there, leaving
+/// the rest of the packet as it was.
+fn reset_xmp_orientation(xmp: &[u8]) -> Option<Vec<u8>> {
+  let mut xmp = std::str::from_utf8(xmp).ok()?.to_string();
+  let forms = [
+    ("tiff:Orientation=\"", "\""),
+    ("tiff:Orientation='", "'"),
+    ("<tiff:Orientation>", "</tiff:Orientation>"),
+  ];
+  for (start, end) in forms.iter() {
+    let mut from = 0;
+    while let Some(pos) = xmp[from..].find(start) {
+      let valstart = from + pos + start.len();
+      let valend = match xmp[valstart..].find(end) {
+        Some(len) => valstart + len,
+        None => break,
+      };
+      xmp.replace_range(valstart..valend, "1");
+      from = valstart + 1;
+    }
+  }
+  Some(xmp.into_bytes())
+}
+
+/// Pick the metadata of a source file that should go into its export. The
+/// exported pixels are already rotated so the orientation is always reset.
+pub fn for_export(file: &str, policy: MetadataPolicy, colorspace: ColorSpace) -> ExportMetadata {
+  let icc = Some(colorspace.icc_profile());
+  if policy == MetadataPolicy::StripAll {
+    return ExportMetadata { icc, ..Default::default() }
+  }
+  let mut fields = Vec::new();
+  let mut xmp = None;
+  if let Some(exif) = read_exif(file) {
+    for field in exif.fields() {
+      if field.tag == XMP_TAG && field.ifd_num == exif::In::PRIMARY {
+        // We can't strip things from inside the XMP so only keep it when
+        // keeping everything, with the same orientation reset as the EXIF
+        if policy == MetadataPolicy::Keep {
+          xmp = match field.value {
+            exif::Value::Byte(ref data) | exif::Value::Undefined(ref data, _) => reset_xmp_orientation(data),
+            _ => None,
+          };
+        }
+      } else if keep_field(field, policy) {
+        fields.push(field.clone());
+      }
+    }
+  }
+  fields.push(exif::Field {
+    tag: exif::Tag::Orientation,
+    ifd_num: exif::In::PRIMARY,
+    value: exif::Value::Short(vec![1]),
+  });
+  fields.push(exif::Field {
+    tag: exif::Tag::Software,
+    ifd_num: exif::In::PRIMARY,
+    value: exif::Value::Ascii(vec![b"chimper".to_vec()]),
+  });
+  // EXIF can only say sRGB or uncalibrated, leaving the rest to the ICC
+  fields.push(exif::Field {
+    tag: exif::Tag::ColorSpace,
+    ifd_num: exif::In::PRIMARY,
+    value: exif::Value::Short(vec![if colorspace == ColorSpace::Srgb { 1 } else { 0xffff }]),
+  });
+  ExportMetadata { fields, xmp, icc }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn reset(xmp: &str) -> String {
+    String::from_utf8(reset_xmp_orientation(xmp.as_bytes()).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn xmp_orientation_attribute() {
+    assert_eq!(
+      reset(r#"<rdf:Description tiff:Orientation="6" tiff:Make="Canon"/>"#),
+      r#"<rdf:Description tiff:Orientation="1" tiff:Make="Canon"/>"#,
+    );
+    assert_eq!(reset("<rdf:Description tiff:Orientation='8'/>"), "<rdf:Description tiff:Orientation='1'/>");
+  }
+
+  #[test]
+  fn xmp_orientation_element() {
+    assert_eq!(
+      reset("<a><tiff:Orientation>3</tiff:Orientation></a><b><tiff:Orientation>8</tiff:Orientation></b>"),
+      "<a><tiff:Orientation>1</tiff:Orientation></a><b><tiff:Orientation>1</tiff:Orientation></b>",
+    );
+    assert_eq!(reset("<x:xmpmeta>no orientation</x:xmpmeta>"), "<x:xmpmeta>no orientation</x:xmpmeta>");
+  }
+
+  #[test]
+  fn xmp_orientation_malformed() {
+    // The packet is kept with what could be reset, the rest stays as it was
+    assert_eq!(
+      reset(r#"<d tiff:Orientation="6"/><d tiff:Orientation="8"#),
+      r#"<d tiff:Orientation="1"/><d tiff:Orientation="8"#,
+    );
+    assert_eq!(
+      reset("<tiff:Orientation>6</tiff:Orientation><tiff:Orientation>8"),
+      "<tiff:Orientation>1</tiff:Orientation><tiff:Orientation>8",
+    );
+    assert!(reset_xmp_orientation(&[0xff, 0xfe, 0x00]).is_none());
+  }
+}
@@ -1,2 +1,12 @@
 pub mod cache;
+pub mod colorspace;
+pub mod directory;
+pub mod diskcache;
 pub mod export;
+pub mod histogram;
+pub mod metadata;
+pub mod prefetch;
+pub mod sidecar;
+#[cfg(test)]
+pub mod testutil;
+pub mod watcher;
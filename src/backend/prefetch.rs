@@ -0,0 +1,134 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use crate::backend::cache::{ImageCache, RequestedImage};
+use crate::backend::directory;
+use crate::backend::sidecar;
+
+struct PrefetchState {
+  jobs: VecDeque<RequestedImage>,
+  // Last directory listing, so we don't hit the disk on every request
+  listing: Option<(PathBuf, Vec<PathBuf>)>,
+  closed: bool,
+}
+
+/// Warms an `ImageCache` with the files around the one being looked at so that
+/// stepping through a directory doesn't have to wait for a full decode.
+///
+/// Jobs only get queued once the real request they came from has been served
+/// and are dropped as soon as a new real request comes in. A job that's
+/// already running can't be interrupted but if it's for the image that was
+/// requested the cache waits for it instead of processing it twice.
+pub struct Prefetcher {
+  state: Mutex<PrefetchState>,
+  available: Condvar,
+}
+
+impl Prefetcher {
+  pub fn new(cache: Arc<ImageCache>) -> Arc<Self> {
+    let prefetcher = Arc::new(Self {
+      state: Mutex::new(PrefetchState {
+        jobs: VecDeque::new(),
+        listing: None,
+        closed: false,
+      }),
+      available: Condvar::new(),
+    });
+    let worker = prefetcher.clone();
+    std::thread::spawn(move || {
+      while let Some(req) = worker.pop() {
+        log::debug!("prefetching {}", req.file);
+        cache.prefetch(req);
+      }
+    });
+    prefetcher
+  }
+
+  /// Drop any pending work to make way for a real request
+  pub fn cancel(&self) {
+    self.state.lock().unwrap().jobs.clear();
+  }
+
+  /// Stop the worker thread once it finishes what it's doing
+  pub fn close(&self) {
+    let mut state = self.state.lock().unwrap();
+    state.jobs.clear();
+    state.closed = true;
+    self.available.notify_all();
+  }
+
+  /// Go by the order the directory is being browsed in, otherwise the
+  /// neighbours are the ones by name
+  pub fn set_listing(&self, dir: PathBuf, files: Vec<PathBuf>) {
+    self.state.lock().unwrap().listing = Some((dir, files));
+  }
+
+  /// Queue the next and previous files in directory order at the same size
+  /// as a request that was just served
+  pub fn prefetch_around(&self, req: &RequestedImage) {
+    let path = Path::new(&req.file);
+    let dir = match path.parent() {
+      Some(dir) => dir.to_path_buf(),
+      None => return,
+    };
+
+    let mut state = self.state.lock().unwrap();
+    // A file the GUI's filter leaves out is still in its directory's listing,
+    // it just has no neighbours in it
+    let stale = match state.listing {
+      Some((ref listdir, _)) => *listdir != dir,
+      None => true,
+    };
+    if stale {
+      state.listing = Some((dir.clone(), directory::list_images(&dir)));
+    }
+    let files = &state.listing.as_ref().unwrap().1;
+    let pos = match files.iter().position(|f| f == path) {
+      Some(pos) => pos,
+      None => return,
+    };
+
+    // Going forward is the most common so do that one first
+    let mut neighbours = Vec::new();
+    if pos + 1 < files.len() {
+      neighbours.push(files[pos+1].clone());
+    }
+    if pos > 0 {
+      neighbours.push(files[pos-1].clone());
+    }
+
+    state.jobs.clear();
+    for file in neighbours {
+      let file = match file.to_str() {
+        Some(file) => file.to_string(),
+        None => continue,
+      };
+      // Ask for the same ops the GUI will use when it opens the file
+      let ops = sidecar::load_ops(&file);
+      state.jobs.push_back(RequestedImage {
+        file,
+        width: req.width,
+        height: req.height,
+        ops,
+        region: None,
+        sensor_clipping: false,
+        before: false,
+        thumbnail: false,
+      });
+    }
+    self.available.notify_one();
+  }
+
+  fn pop(&self) -> Option<RequestedImage> {
+    let mut state = self.state.lock().unwrap();
+    loop {
+      if state.closed {
+        return None
+      }
+      if let Some(req) = state.jobs.pop_front() {
+        return Some(req)
+      }
+      state = self.available.wait(state).unwrap();
+    }
+  }
+}
@@ -0,0 +1,77 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use serde_derive::{Serialize, Deserialize};
+
+// Bump this whenever the on-disk layout changes in an incompatible way
+pub const SIDECAR_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Sidecar {
+  version: u32,
+  ops: imagepipe::PipelineOps,
+}
+
+pub fn sidecar_path(file: &str) -> String {
+  format!("{}.chimper", file)
+}
+
+pub fn load_ops(file: &str) -> Option<imagepipe::PipelineOps> {
+  let path = sidecar_path(file);
+  if !Path::new(&path).is_file() {
+    return None
+  }
+  load_from(&path)
+}
+
+/// Load the ops from a given sidecar file, e.g. to use it as a preset
+pub fn load_from(path: &str) -> Option<imagepipe::PipelineOps> {
+  let data = match fs::read_to_string(&path) {
+    Ok(data) => data,
+    Err(e) => {
+      log::error!("Error reading sidecar \"{}\": {}", path, e);
+      return None
+    },
+  };
+  // Look at the version first so a sidecar from a newer chimper gives a clear
+  // message instead of a confusing parse error
+  let value: serde_yaml::Value = match serde_yaml::from_str(&data) {
+    Ok(value) => value,
+    Err(e) => {
+      log::error!("Error parsing sidecar \"{}\": {}", path, e);
+      return None
+    },
+  };
+  let version = value.get("version").and_then(|v| v.as_u64()).unwrap_or(0);
+  if version == 0 || version > SIDECAR_VERSION as u64 {
+    log::error!("Sidecar \"{}\" has unsupported version {}", path, version);
+    return None
+  }
+  match serde_yaml::from_value::<Sidecar>(value) {
+    Ok(sidecar) => {
+      log::debug!("Loaded ops from \"{}\"", path);
+      Some(sidecar.ops)
+    },
+    Err(e) => {
+      log::error!("Error parsing sidecar \"{}\": {}", path, e);
+      None
+    },
+  }
+}
+
+pub fn save_ops(file: &str, ops: &imagepipe::PipelineOps) -> io::Result<()> {
+  let path = sidecar_path(file);
+  let sidecar = Sidecar {
+    version: SIDECAR_VERSION,
+    ops: ops.clone(),
+  };
+  let data = serde_yaml::to_string(&sidecar)
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+  // Write to a temporary file and rename it over the old one so that a crash
+  // halfway through never leaves a truncated sidecar behind
+  let tmppath = format!("{}.tmp", path);
+  fs::write(&tmppath, data)?;
+  fs::rename(&tmppath, &path)?;
+  log::debug!("Saved ops for \"{}\" into \"{}\"", file, path);
+  Ok(())
+}
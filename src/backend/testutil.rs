@@ -0,0 +1,43 @@
+// Helpers shared by the unit tests of several modules
+
+extern crate imagepipe;
+extern crate image;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The default ops of a small blank image, built without touching the disk
+pub fn default_ops() -> imagepipe::PipelineOps {
+  let image = image::DynamicImage::new_rgb8(4, 3);
+  imagepipe::Pipeline::new_from_source(imagepipe::ImageSource::Other(image)).unwrap().ops
+}
+
+/// A fresh directory of its own under the system temporary one, removed again
+/// once dropped
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+  pub fn new(name: &str) -> Self {
+    let dir = std::env::temp_dir().join(format!("chimper-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    TestDir(dir)
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.0
+  }
+
+  /// Create a file in the directory with some contents and return its path
+  pub fn file(&self, name: &str, contents: &[u8]) -> PathBuf {
+    let path = self.0.join(name);
+    fs::write(&path, contents).unwrap();
+    path
+  }
+}
+
+impl Drop for TestDir {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.0);
+  }
+}
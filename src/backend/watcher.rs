@@ -0,0 +1,75 @@
+extern crate notify;
+use self::notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::backend::directory;
+
+// Wait for things to settle before reporting, copies and syncs usually write a
+// file in several steps
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Watches the directory being browsed and reports the images in it that get
+/// written, created, removed or renamed
+pub struct DirWatcher {
+  watcher: Option<RecommendedWatcher>,
+  dir: Option<PathBuf>,
+}
+
+impl DirWatcher {
+  pub fn new<F>(on_change: F) -> Self
+  where F: Fn(PathBuf) + Send + 'static {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let watcher = match notify::watcher(tx, DEBOUNCE) {
+      Ok(watcher) => Some(watcher),
+      Err(e) => {
+        log::error!("Can't watch for file changes: {}", e);
+        None
+      },
+    };
+    // This goes away on its own once the watcher is dropped
+    std::thread::spawn(move || {
+      let report = |path: PathBuf| {
+        // Skip our own sidecars and anything else we wouldn't show
+        if directory::is_supported(&path) {
+          log::debug!("{} changed on disk", path.display());
+          on_change(path);
+        }
+      };
+      for event in rx {
+        match event {
+          DebouncedEvent::Write(path) |
+          DebouncedEvent::Create(path) |
+          DebouncedEvent::Remove(path) => report(path),
+          DebouncedEvent::Rename(from, to) => {
+            report(from);
+            report(to);
+          },
+          DebouncedEvent::Error(e, path) => {
+            log::error!("Error watching {:?}: {}", path, e);
+          },
+          _ => {},
+        }
+      }
+    });
+    Self {
+      watcher,
+      dir: None,
+    }
+  }
+
+  /// Switch to watching a different directory
+  pub fn watch(&mut self, dir: &Path) {
+    if self.dir.as_deref() == Some(dir) {
+      return
+    }
+    if let Some(ref mut watcher) = self.watcher {
+      if let Some(ref olddir) = self.dir {
+        let _ = watcher.unwatch(olddir);
+      }
+      if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+        log::error!("Can't watch \"{}\": {}", dir.display(), e);
+      }
+    }
+    self.dir = Some(dir.to_path_buf());
+  }
+}
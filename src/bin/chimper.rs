@@ -1,15 +1,197 @@
 extern crate chimper;
 use std::env;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use chimper::backend::export::*;
+use chimper::backend::sidecar;
 
 fn usage() {
   eprintln!("Usage: chimper [file or dir]");
+  eprintln!("       chimper export [options] <files...>");
+  eprintln!();
+  eprintln!("Export options:");
+  eprintln!("  --ops <sidecar|default|file>     edits to apply, each file's sidecar by default");
+  eprintln!("  --format <jpeg|png|tiff|webp>    output format, jpeg by default");
+  eprintln!("  --quality <1-100>                jpeg quality, 90 by default");
+  eprintln!("  --bits <8|16>                    bit depth for png and tiff, 8 by default");
+  eprintln!("  --subsampling <444|422|420>      jpeg chroma subsampling, 444 by default");
+  eprintln!("  --colorspace <srgb|p3|adobergb|prophoto|rec2020linear>");
+  eprintln!("                                   output color space, srgb by default,");
+  eprintln!("                                   prophoto and rec2020linear need 16 bits");
+  eprintln!("  --metadata <keep|nogps|nopersonal|none>");
+  eprintln!("                                   source metadata to copy, keep by default");
+  eprintln!("  --out <dir>                      output directory, next to the source by default");
+  eprintln!("  --name <template>                output file name, \"{{stem}}.{{ext}}\" by default");
+  eprintln!("  --exists <suffix|skip|overwrite> what to do when the output exists, suffix by default");
+  eprintln!("  --threads <n>                    how many files to export at once");
+}
+
+fn fail(code: i32, msg: &str) -> ! {
+  log::error!("{}", msg);
+  eprintln!("error: {}", msg);
+  usage();
+  std::process::exit(code);
+}
+
+enum OpsSource {
+  Sidecar,
+  Default,
+  Preset(imagepipe::PipelineOps),
+}
+
+fn run_export(args: &[String]) {
+  let mut settings = ExportSettings::default();
+  let mut destination = ExportDestination::default();
+  let mut opsource = OpsSource::Sidecar;
+  let mut files = Vec::new();
+  let mut nworkers = default_workers();
+
+  let mut args = args.iter();
+  while let Some(arg) = args.next() {
+    if !arg.starts_with("--") {
+      files.push(arg.clone());
+      continue
+    }
+    let value = match args.next() {
+      Some(value) => value.as_str(),
+      None => fail(1, &format!("missing value for {}", arg)),
+    };
+    match arg.as_str() {
+      "--ops" => opsource = match value {
+        "sidecar" => OpsSource::Sidecar,
+        "default" => OpsSource::Default,
+        file => match sidecar::load_from(file) {
+          Some(ops) => OpsSource::Preset(ops),
+          None => fail(2, &format!("couldn't load ops from \"{}\"", file)),
+        },
+      },
+      "--format" => settings.format = match value {
+        "jpeg" | "jpg" => ExportFormat::Jpeg,
+        "png" => ExportFormat::Png,
+        "tiff" | "tif" => ExportFormat::Tiff,
+        "webp" => ExportFormat::WebP,
+        _ => fail(1, &format!("unknown format \"{}\"", value)),
+      },
+      "--quality" => settings.quality = match value.parse::<u8>() {
+        Ok(val) if (1..=100).contains(&val) => val,
+        _ => fail(1, &format!("invalid quality \"{}\"", value)),
+      },
+      "--bits" => settings.bitdepth = match value {
+        "8" => BitDepth::Eight,
+        "16" => BitDepth::Sixteen,
+        _ => fail(1, &format!("invalid bit depth \"{}\"", value)),
+      },
+      "--subsampling" => settings.subsampling = match value {
+        "444" => ChromaSubsampling::Yuv444,
+        "422" => ChromaSubsampling::Yuv422,
+        "420" => ChromaSubsampling::Yuv420,
+        _ => fail(1, &format!("invalid subsampling \"{}\"", value)),
+      },
+      "--colorspace" => settings.colorspace = match value {
+        "srgb" => ColorSpace::Srgb,
+        "p3" => ColorSpace::DisplayP3,
+        "adobergb" => ColorSpace::AdobeRgb,
+        "prophoto" => ColorSpace::ProPhoto,
+        "rec2020linear" => ColorSpace::LinearRec2020,
+        _ => fail(1, &format!("invalid color space \"{}\"", value)),
+      },
+      "--metadata" => settings.metadata = match value {
+        "keep" => MetadataPolicy::Keep,
+        "nogps" => MetadataPolicy::StripGps,
+        "nopersonal" => MetadataPolicy::StripPersonal,
+        "none" => MetadataPolicy::StripAll,
+        _ => fail(1, &format!("invalid metadata policy \"{}\"", value)),
+      },
+      "--out" => destination.directory = Some(value.to_string()),
+      "--name" => destination.template = value.to_string(),
+      "--exists" => destination.collision = match value {
+        "suffix" => CollisionPolicy::Suffix,
+        "skip" => CollisionPolicy::Skip,
+        "overwrite" => CollisionPolicy::Overwrite,
+        _ => fail(1, &format!("invalid collision policy \"{}\"", value)),
+      },
+      "--threads" => nworkers = match value.parse::<usize>() {
+        Ok(val) if val > 0 => val,
+        _ => fail(1, &format!("invalid number of threads \"{}\"", value)),
+      },
+      _ => fail(1, &format!("unknown option {}", arg)),
+    }
+  }
+
+  if files.is_empty() {
+    fail(1, "no files to export");
+  }
+  if settings.colorspace() != settings.colorspace {
+    fail(1, &format!("{} needs a 16 bit png or tiff", settings.colorspace.name()));
+  }
+
+  let failed = Arc::new(AtomicUsize::new(0));
+  let queue = ExportQueue::new();
+  for (num, file) in files.iter().enumerate() {
+    if !PathBuf::from(file).is_file() {
+      log::error!("not a file: \"{}\"", file);
+      eprintln!("{}: not a file", file);
+      failed.fetch_add(1, Ordering::SeqCst);
+      continue
+    }
+    let ops = match opsource {
+      OpsSource::Sidecar => sidecar::load_ops(file),
+      OpsSource::Default => None,
+      OpsSource::Preset(ref ops) => Some(ops.clone()),
+    };
+    let req = RequestedExport {
+      id: num as u64,
+      file: file.clone(),
+      ops,
+      settings,
+      destination: destination.clone(),
+      seq: num as u32 + 1,
+    };
+    queue.push(req);
+  }
+  queue.close();
+
+  let skipped = Arc::new(AtomicUsize::new(0));
+  let workerfailed = failed.clone();
+  let workerskipped = skipped.clone();
+  let workers = spawn_workers(&queue, nworkers, CancelledExports::default(), move |status| {
+    match status.state {
+      ExportState::Done(path) => println!("{} -> {}", status.file, path.display()),
+      ExportState::Skipped(path) => {
+        println!("{} skipped, {} exists", status.file, path.display());
+        workerskipped.fetch_add(1, Ordering::SeqCst);
+      },
+      ExportState::Failed(e) => {
+        eprintln!("{}: {}", status.file, e);
+        workerfailed.fetch_add(1, Ordering::SeqCst);
+      },
+      _ => {},
+    }
+  });
+  for worker in workers {
+    let _ = worker.join();
+  }
+
+  // Skipping is what was asked for so it doesn't make the run fail
+  let skipped = skipped.load(Ordering::SeqCst);
+  if skipped > 0 {
+    eprintln!("skipped {} file(s) whose output already existed", skipped);
+  }
+  if failed.load(Ordering::SeqCst) > 0 {
+    std::process::exit(4);
+  }
 }
 
 fn main() {
   env_logger::init();
 
   let args: Vec<_> = env::args().collect();
+  if args.len() > 1 && args[1] == "export" {
+    run_export(&args[2..]);
+    return
+  }
+
   if args.len() > 2 {
     log::error!("called with wrong arguments");
     usage();
@@ -1,17 +1,20 @@
 extern crate conrod_core;
-use conrod_core::{widget, Colorable, Positionable, Sizeable, Borderable, Widget, color};
+use conrod_core::{widget, Colorable, Positionable, Sizeable, Borderable, Labelable, Widget, color};
 extern crate imagepipe;
 
 use crate::frontend::main::Chimper;
 use crate::frontend::main::DisplayableState;
 use crate::frontend::ops;
-use crate::frontend::widgets::ImageView;
+use crate::backend::colorspace;
+use crate::backend::directory;
+use crate::frontend::widgets::{ImageView, ViewEvent, ViewTile, HistogramView, Thumbnails, Thumbnail, ThumbnailEvent};
 
 pub fn draw_gui(chimper: &mut Chimper, ui: &mut conrod_core::Ui) -> bool {
   let ui = &mut ui.set_widgets();
 
   let sidewidth = chimper.sidewidth * ((chimper.use_sidepane as u8) as f64);
   let dragwidth = chimper.dragwidth * ((chimper.use_sidepane as u8) as f64);
+  let mut picked = None;
   {
     let ids = &mut chimper.ids;
 
@@ -39,20 +42,96 @@ pub fn draw_gui(chimper: &mut Chimper, ui: &mut conrod_core::Ui) -> bool {
     };
 
     if let Some(image) = image {
-      let scale = (image.width as f64)/(image.height as f64);
-      let mut width = (ui.w_of(ids.imgcanvas).unwrap() - img_padding).min(image.width as f64);
-      let mut height = (ui.h_of(ids.imgcanvas).unwrap() - img_padding).min(image.height as f64);
-      if width/height > scale {
-        width = height * scale;
-      } else {
-        height = width / scale;
+      let mut width = ui.w_of(ids.imgcanvas).unwrap() - img_padding;
+      let mut height = ui.h_of(ids.imgcanvas).unwrap() - img_padding;
+      if chimper.crops.is_some() {
+        // Cropping shows the whole image with the handles around it
+        let scale = (image.width as f64)/(image.height as f64);
+        width = width.min(image.width as f64);
+        height = height.min(image.height as f64);
+        if width/height > scale {
+          width = height * scale;
+        } else {
+          height = width / scale;
+        }
       }
-      for crops in ImageView::new(image.id, chimper.crops)
+      let native = chimper.native_size.map(|(w, h)| (w as f64, h as f64));
+      let tiles = chimper.tiles.iter().map(|(_, tile)| ViewTile {
+        id: tile.id,
+        dims: (tile.width as f64, tile.height as f64),
+        rect: tile.region.fractions(),
+      }).collect();
+      for event in ImageView::new(image.id, chimper.crops)
+        .image_dims((image.width as f64, image.height as f64))
+        .native_dims(native)
+        .zoom(chimper.zoom)
+        .pan(chimper.pan)
+        .tiles(tiles)
+        .overlay(if chimper.clipping.enabled { Some(chimper.overlayid) } else { None })
+        .picking(chimper.wb_picker)
+        .compare(chimper.compare)
+        .before(chimper.before.as_ref().map(|b| (b.id, (b.width as f64, b.height as f64))))
         .w_h(width, height)
         .middle_of(ids.imgcanvas)
         .set(ids.raw_image, ui) {
-        chimper.crops = Some(crops);
+        match event {
+          ViewEvent::Crop(crops) => chimper.crops = Some(crops),
+          ViewEvent::View(zoom, pan) => {
+            chimper.zoom = zoom;
+            chimper.pan = pan;
+          },
+          ViewEvent::Hover(hover) => chimper.hover = hover,
+          ViewEvent::Pick(rect) => picked = Some(rect),
+          ViewEvent::Compare(compare) => chimper.compare = compare,
+        }
       }
+
+      // Show what's under the cursor, in the file's own pixels. Zoomed in the
+      // detail is in the tiles on top of the fit render so look there first.
+      let tiles = &chimper.tiles;
+      let hovered = chimper.hover.and_then(|(x, y)| {
+        let tile = tiles.iter().map(|(_, tile)| tile).find(|tile| {
+          let [left, top, right, bottom] = tile.region.fractions();
+          x >= left && x < right && y >= top && y < bottom
+        });
+        let rgb = match tile {
+          Some(tile) => {
+            let [left, top, right, bottom] = tile.region.fractions();
+            pixel_at(&tile.output.image, (x - left) / (right - left), (y - top) / (bottom - top))
+          },
+          None => pixel_at(&image.output.image, x, y),
+        };
+        rgb.map(|rgb| (x, y, rgb))
+      });
+      if let Some((x, y, rgb)) = hovered {
+        let output = &image.output;
+        let lab = colorspace::srgb_to_lab(rgb);
+        let coords = match output.source_position(x, y) {
+          Some((sx, sy)) => format!("x {:.0}  y {:.0}", sx.floor(), sy.floor()),
+          None => format!("x {:.1}%  y {:.1}%", x * 100.0, y * 100.0),
+        };
+        widget::Text::new(&format!("{}    RGB {} {} {}    Lab {:.1} {:.1} {:.1}",
+          coords, rgb[0], rgb[1], rgb[2], lab[0], lab[1], lab[2]))
+          .color(color::WHITE)
+          .font_size(14)
+          .bottom_left_with_margins_on(ids.imgcanvas, 6.0, 10.0)
+          .set(ids.inspector, ui);
+      }
+    }
+
+    // Instead of a blank area say what went wrong
+    if let DisplayableState::Broken(ref file, ref error) = chimper.image {
+      let name = std::path::Path::new(file).file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| file.clone());
+      widget::Text::new(&format!("Couldn't open {}\n\n{}", name, error))
+        .color(color::LIGHT_GREY)
+        .font_size(18)
+        .center_justify()
+        .wrap_by_word()
+        .w(ui.w_of(ids.imgcanvas).unwrap() - img_padding - 40.0)
+        .middle_of(ids.imgcanvas)
+        .set(ids.broken_text, ui);
     }
 
     if sidewidth > 0.0 {
@@ -64,18 +143,58 @@ pub fn draw_gui(chimper: &mut Chimper, ui: &mut conrod_core::Ui) -> bool {
         chimper.sideopt = !chimper.sideopt;
       }
 
+      // Next to the logo show how the render is spread out
+      if let Some(image) = image {
+        let togglewidth = 50.0;
+        HistogramView::new(&image.output.histogram)
+          .log(chimper.histogram_log)
+          .w_h(sidewidth - 78.0 - togglewidth - 6.0 * 4.0, 88.0)
+          .top_left_with_margins_on(ids.settop, 6.0, 6.0)
+          .set(ids.histogram, ui);
+        for event in widget::toggle::Toggle::new(chimper.histogram_log)
+          .w_h(togglewidth, 30.0)
+          .label(if chimper.histogram_log { "LOG" } else { "LIN" })
+          .top_left_with_margins_on(ids.settop, 6.0, sidewidth - 78.0 - togglewidth - 6.0 * 2.0)
+          .set(ids.histogram_log, ui)
+        {
+          chimper.histogram_log = event;
+        }
+      }
+
       if chimper.sideopt {
+        for event in widget::toggle::Toggle::new(chimper.grid)
+          .w_h(110.0, 30.0)
+          .label(if chimper.grid { "Thumbnails" } else { "Files" })
+          .top_left_with_margins_on(ids.setcont, 6.0, 6.0)
+          .set(ids.browser_mode, ui)
+        {
+          chimper.grid = event;
+        }
+      }
+      if chimper.sideopt && !chimper.grid {
         let directory = chimper.directory.as_path();
-        for event in widget::FileNavigator::all(&directory)
+        let extensions = chimper.filter.extensions();
+        let extensions: Vec<&str> = extensions.iter().map(|e| e.as_str()).collect();
+        for event in widget::FileNavigator::with_extension(&directory, &extensions)
           .color(conrod_core::color::LIGHT_BLUE)
           .font_size(16)
-          .kid_area_wh_of(ids.setcont)
-          .middle_of(ids.setcont)
+          .wh(browser_wh(ui, ids.setcont))
+          .mid_bottom_of(ids.setcont)
           //.show_hidden_files(true)  // Use this to show hidden files
           .set(ids.filenav, ui)
         {
           match event {
             conrod_core::widget::file_navigator::Event::ChangeSelection(pbuf) => {
+              if let Some(path) = pbuf.first() {
+                let dir = if path.is_dir() { Some(path.as_path()) } else { path.parent() };
+                if let Some(dir) = dir {
+                  chimper.browsed = dir.to_path_buf();
+                }
+              }
+              chimper.selection = pbuf.iter()
+                .filter(|path| path.is_file())
+                .filter_map(|path| path.to_str().map(|p| p.to_string()))
+                .collect();
               if pbuf.len() > 0 {
                 let path = pbuf[0].as_path();
                 if path.is_file() {
@@ -91,9 +210,111 @@ pub fn draw_gui(chimper: &mut Chimper, ui: &mut conrod_core::Ui) -> bool {
     }
   }
 
+  if sidewidth > 0.0 && chimper.sideopt {
+    let setcont = chimper.ids.setcont;
+    let names: Vec<&str> = directory::Filter::ALL.iter().map(|f| f.0).collect();
+    let selected = directory::Filter::ALL.iter().position(|f| f.1 == chimper.filter);
+    for event in widget::drop_down_list::DropDownList::new(&names, selected)
+      .w_h(120.0, 30.0)
+      .top_left_with_margins_on(setcont, 6.0, 122.0)
+      .set(chimper.ids.browser_filter, ui)
+    {
+      chimper.set_filter(directory::Filter::ALL[event].1);
+    }
+    // The file list always goes by name, this is for the grid and stepping
+    let names: Vec<&str> = directory::Sort::ALL.iter().map(|s| s.0).collect();
+    let selected = directory::Sort::ALL.iter().position(|s| s.1 == chimper.sort);
+    for event in widget::drop_down_list::DropDownList::new(&names, selected)
+      .w_h(130.0, 30.0)
+      .top_left_with_margins_on(setcont, 6.0, 248.0)
+      .set(chimper.ids.browser_sort, ui)
+    {
+      chimper.set_sort(directory::Sort::ALL[event].1);
+    }
+    // The grid shows the directory of the image and the file list whatever
+    // was picked
+    let (dir, count) = if chimper.grid {
+      (chimper.current_dir(), chimper.images().len())
+    } else {
+      (chimper.browsed.clone(), chimper.browsed_count())
+    };
+    let name = dir.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    widget::Text::new(&format!("{} in {}", count, name))
+      .font_size(14)
+      .no_line_wrap()
+      .top_left_with_margins_on(setcont, 12.0, 388.0)
+      .set(chimper.ids.browser_count, ui);
+  }
+
+  if sidewidth > 0.0 && chimper.sideopt && chimper.grid {
+    let images = chimper.images().to_vec();
+    let selected = chimper.file.as_ref()
+      .and_then(|file| images.iter().position(|path| path.to_str() == Some(file)));
+    let items: Vec<Thumbnail> = images.iter().map(|path| {
+      let thumbnail = path.to_str().and_then(|file| chimper.thumbnails.get(file));
+      Thumbnail {
+        label: short_name(path, 20),
+        image: thumbnail.map(|t| (t.id, (t.width as f64, t.height as f64))),
+      }
+    }).collect();
+    for event in Thumbnails::new(&items, selected)
+      .size(128.0)
+      .wh(browser_wh(ui, chimper.ids.setcont))
+      .mid_bottom_of(chimper.ids.setcont)
+      .set(chimper.ids.thumbnails, ui)
+    {
+      match event {
+        ThumbnailEvent::Select(pos) => {
+          if let Some(file) = images[pos].to_str() {
+            log::info!("Loading file {:?}", file);
+            chimper.selection = vec![file.to_string()];
+            chimper.file = Some(file.to_string());
+          }
+        },
+        ThumbnailEvent::Visible(first, last) => chimper.thumbnails_visible = (first, last),
+      }
+    }
+  }
+
+  if let Some(rect) = picked {
+    ops::tolab::pick_neutral(chimper, rect);
+    chimper.wb_picker = false;
+  }
+
   if sidewidth > 0.0 && !chimper.sideopt {
     ops::draw_gui(chimper, ui);
   }
 
   false
 }
+
+// Room left for the file browser under its mode toggle
+// The pixel at a point given as fractions of the image, if it has any
+fn pixel_at(image: &imagepipe::SRGBImage, x: f64, y: f64) -> Option<[u8; 3]> {
+  if image.width == 0 || image.height == 0 {
+    return None
+  }
+  let px = ((x * image.width as f64) as usize).min(image.width - 1);
+  let py = ((y * image.height as f64) as usize).min(image.height - 1);
+  let pos = (py * image.width + px) * 3;
+  image.data.get(pos..pos+3).map(|pixel| [pixel[0], pixel[1], pixel[2]])
+}
+
+fn browser_wh(ui: &conrod_core::UiCell, setcont: widget::Id) -> [f64; 2] {
+  let area = ui.kid_area_of(setcont);
+  [
+    area.map(|r| r.w()).unwrap_or(0.0),
+    (area.map(|r| r.h()).unwrap_or(0.0) - 42.0).max(0.0),
+  ]
+}
+
+// A file name cut down to fit under a thumbnail
+fn short_name(path: &std::path::Path, max: usize) -> String {
+  let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+  if name.chars().count() <= max {
+    name
+  } else {
+    let start: String = name.chars().take(max - 1).collect();
+    format!("{}…", start)
+  }
+}
@@ -0,0 +1,233 @@
+extern crate imagepipe;
+use self::imagepipe::{ImageOp, PipelineOps};
+use std::collections::VecDeque;
+
+// How many undo steps to keep around per image
+static MAX_HISTORY: usize = 100;
+
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+  pub name: String,
+  pub ops: PipelineOps,
+}
+
+/// Bounded undo/redo history of the ops of the image being edited
+#[derive(Debug, Clone, Default)]
+pub struct History {
+  undo: VecDeque<HistoryEntry>,
+  redo: Vec<HistoryEntry>,
+  current: Option<HistoryEntry>,
+}
+
+// Name a history step after the ops it touched, using the same names as the
+// side pane headers
+fn describe(from: &PipelineOps, to: &PipelineOps) -> String {
+  let mut names = Vec::new();
+  if from.gofloat.shash() != to.gofloat.shash() || from.demosaic.shash() != to.demosaic.shash() {
+    names.push("raw input");
+  }
+  if from.tolab.shash() != to.tolab.shash() {
+    names.push("colorspace");
+  }
+  if from.basecurve.shash() != to.basecurve.shash() {
+    names.push("basecurve");
+  }
+  if from.transform.shash() != to.transform.shash() {
+    names.push("transform");
+  }
+  if from.rotatecrop.shash() != to.rotatecrop.shash() {
+    names.push("rotate and crop");
+  }
+  if names.is_empty() {
+    "edit".to_string()
+  } else {
+    names.join(", ")
+  }
+}
+
+impl History {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Forget everything, used when switching to another image
+  pub fn clear(&mut self) {
+    *self = Self::new();
+  }
+
+  /// Record the current state of the ops. While `dragging` is set nothing is
+  /// committed so that a whole drag of a slider or curve point ends up as a
+  /// single undo step once the mouse is released.
+  pub fn update(&mut self, ops: &PipelineOps, dragging: bool) {
+    let current = match self.current {
+      None => {
+        self.current = Some(HistoryEntry {
+          name: "open".to_string(),
+          ops: ops.clone(),
+        });
+        return
+      },
+      Some(ref current) => current,
+    };
+    if current.ops == *ops || dragging {
+      return
+    }
+    let entry = HistoryEntry {
+      name: describe(&current.ops, ops),
+      ops: ops.clone(),
+    };
+    let previous = std::mem::replace(&mut self.current, Some(entry)).unwrap();
+    self.undo.push_back(previous);
+    if self.undo.len() > MAX_HISTORY {
+      self.undo.pop_front();
+    }
+    self.redo.clear();
+  }
+
+  pub fn can_undo(&self) -> bool {
+    !self.undo.is_empty()
+  }
+
+  pub fn can_redo(&self) -> bool {
+    !self.redo.is_empty()
+  }
+
+  /// Step back once, returning the ops that should now be applied
+  pub fn undo(&mut self) -> Option<PipelineOps> {
+    let entry = self.undo.pop_back()?;
+    if let Some(current) = self.current.take() {
+      self.redo.push(current);
+    }
+    let ops = entry.ops.clone();
+    self.current = Some(entry);
+    Some(ops)
+  }
+
+  /// Step forward once, returning the ops that should now be applied
+  pub fn redo(&mut self) -> Option<PipelineOps> {
+    let entry = self.redo.pop()?;
+    if let Some(current) = self.current.take() {
+      self.undo.push_back(current);
+    }
+    let ops = entry.ops.clone();
+    self.current = Some(entry);
+    Some(ops)
+  }
+
+  /// All the entries from oldest to newest and the position of the current one
+  pub fn entries(&self) -> (Vec<&HistoryEntry>, usize) {
+    let mut entries: Vec<&HistoryEntry> = self.undo.iter().collect();
+    let pos = entries.len();
+    entries.extend(self.current.iter());
+    entries.extend(self.redo.iter().rev());
+    (entries, pos)
+  }
+
+  /// Move to a given position as returned by `entries()`
+  pub fn jump(&mut self, pos: usize) -> Option<PipelineOps> {
+    let mut ops = None;
+    while self.undo.len() > pos {
+      ops = self.undo();
+    }
+    while self.undo.len() < pos && self.can_redo() {
+      ops = self.redo();
+    }
+    ops
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::backend::testutil;
+
+  // The default ops with the left crop standing in for an edit
+  fn ops() -> impl Fn(f32) -> PipelineOps {
+    let base = testutil::default_ops();
+    move |crop| {
+      let mut ops = base.clone();
+      ops.rotatecrop.crop_left = crop;
+      ops
+    }
+  }
+
+  #[test]
+  fn undo_and_redo() {
+    let ops = ops();
+    let mut history = History::new();
+    history.update(&ops(0.0), false);
+    assert!(!history.can_undo());
+    history.update(&ops(0.1), false);
+    history.update(&ops(0.2), false);
+    assert!(history.can_undo());
+    assert!(!history.can_redo());
+
+    assert_eq!(history.undo(), Some(ops(0.1)));
+    assert_eq!(history.undo(), Some(ops(0.0)));
+    assert_eq!(history.undo(), None);
+    assert!(history.can_redo());
+    assert_eq!(history.redo(), Some(ops(0.1)));
+    assert_eq!(history.redo(), Some(ops(0.2)));
+    assert_eq!(history.redo(), None);
+  }
+
+  #[test]
+  fn unchanged_ops_and_drags() {
+    let ops = ops();
+    let mut history = History::new();
+    history.update(&ops(0.0), false);
+    history.update(&ops(0.0), false);
+    assert!(!history.can_undo());
+    // A whole drag is a single step once the mouse comes up
+    history.update(&ops(0.1), true);
+    history.update(&ops(0.2), true);
+    assert!(!history.can_undo());
+    history.update(&ops(0.3), false);
+    let (entries, pos) = history.entries();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(pos, 1);
+    assert_eq!(entries[1].name, "rotate and crop");
+    assert_eq!(history.undo(), Some(ops(0.0)));
+  }
+
+  #[test]
+  fn new_edit_drops_redo() {
+    let ops = ops();
+    let mut history = History::new();
+    history.update(&ops(0.0), false);
+    history.update(&ops(0.1), false);
+    history.undo();
+    assert!(history.can_redo());
+    history.update(&ops(0.2), false);
+    assert!(!history.can_redo());
+    assert_eq!(history.undo(), Some(ops(0.0)));
+  }
+
+  #[test]
+  fn bounded() {
+    let ops = ops();
+    let mut history = History::new();
+    for step in 0..MAX_HISTORY + 10 {
+      history.update(&ops(step as f32 / 1000.0), false);
+    }
+    let mut undone = 0;
+    while history.undo().is_some() {
+      undone += 1;
+    }
+    assert_eq!(undone, MAX_HISTORY);
+  }
+
+  #[test]
+  fn jump() {
+    let ops = ops();
+    let mut history = History::new();
+    for step in 0..5 {
+      history.update(&ops(step as f32 / 10.0), false);
+    }
+    assert_eq!(history.jump(1), Some(ops(0.1)));
+    assert_eq!(history.entries().1, 1);
+    assert_eq!(history.jump(3), Some(ops(0.3)));
+    assert_eq!(history.entries().1, 3);
+    assert_eq!(history.jump(3), None);
+  }
+}
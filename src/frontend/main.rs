@@ -9,18 +9,28 @@ use self::glium::glutin::window::Fullscreen;
 extern crate conrod_glium;
 use conrod_glium::Renderer;
 
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::TryRecvError;
 extern crate image;
 
 use crate::frontend::*;
 use crate::backend::cache::*;
 use crate::backend::export::*;
+use crate::backend::sidecar;
+use crate::backend::directory;
+use crate::backend::prefetch::Prefetcher;
+use crate::backend::watcher::DirWatcher;
+use crate::frontend::history::History;
+use crate::frontend::widgets::{Zoom, Compare, Thumbnails};
+use crate::frontend::overlay::{self, ClipSettings};
 
 widget_ids!(
 pub struct ChimperIds {
   background, imgcanvas, dragcanvas, setcanvas, settop, setcont, raw_image, chimper, filenav,
+  broken_text, histogram, histogram_log, inspector, thumbnails, browser_mode, browser_filter,
+  browser_count, browser_sort,
   ops_settings[],
   ops_headers[],
   ops_resets[],
@@ -30,6 +40,9 @@ pub struct ChimperIds {
   op_tolab[],
   op_transform[],
   op_basecurve[],
+  op_history[],
+  op_clipping[],
+  op_export[],
 });
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -41,6 +54,9 @@ pub enum SelectedOp {
   Basecurve,
   Transform,
   RotateCrop,
+  Clipping,
+  History,
+  Export,
 }
 
 #[derive(Debug, Clone)]
@@ -53,6 +69,33 @@ pub struct DisplayableImage {
   pub maxheight: u32,
   pub ops: imagepipe::PipelineOps,
   pub default_ops: imagepipe::PipelineOps,
+  // Kept around to look at the pixels and map them back to the file
+  pub output: std::sync::Arc<ImageOutput>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DisplayableTile {
+  pub file: String,
+  pub region: Region,
+  pub id: conrod_core::image::Id,
+  pub width: u32,
+  pub height: u32,
+  pub maxwidth: u32,
+  pub ops: imagepipe::PipelineOps,
+  // A texture that was dropped to make room for this one
+  pub evicted: Option<conrod_core::image::Id>,
+  // The pixels themselves, for the inspector
+  pub output: std::sync::Arc<ImageOutput>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DisplayableThumbnail {
+  pub file: String,
+  pub id: conrod_core::image::Id,
+  pub width: u32,
+  pub height: u32,
+  // A texture that was dropped to make room for this one
+  pub evicted: Option<conrod_core::image::Id>,
 }
 
 #[derive(Debug, Clone)]
@@ -60,7 +103,7 @@ pub enum DisplayableState {
   Empty,
   Requested(RequestedImage, Option<DisplayableImage>),
   Present(DisplayableImage),
-  Broken(String),
+  Broken(String, LoadError),
 }
 
 pub struct Chimper {
@@ -70,20 +113,69 @@ pub struct Chimper {
   pub use_sidepane: bool,
   pub logoid: conrod_core::image::Id,
   pub temp_tint_image_id: conrod_core::image::Id,
+  pub overlayid: conrod_core::image::Id,
   pub ids: ChimperIds,
   pub sideopt: bool,
   pub directory: std::path::PathBuf,
   pub file: Option<String>,
+  pub selection: Vec<String>,
   pub image: DisplayableState,
   pub ops: Option<(imagepipe::PipelineOps, imagepipe::PipelineOps)>,
+  pub saved_ops: Option<imagepipe::PipelineOps>,
+  pub edits: HashMap<String, (imagepipe::PipelineOps, imagepipe::PipelineOps)>,
+  pub history: History,
   pub selected_op: SelectedOp,
   pub fullscreen: bool,
   pub export_request_tx: std::sync::mpsc::Sender<RequestedExport>,
+  pub export_settings: ExportSettings,
+  pub export_destination: ExportDestination,
+  pub export_seq: u32,
+  pub exports: Vec<ExportStatus>,
+  pub export_cancelled: CancelledExports,
+  pub next_export_id: u64,
+  pub export_shared_ops: bool,
   pub crops: Option<(f64,f64,f64,f64)>,
+  // The file on screen changed on disk and needs to be requested again
+  pub reload: bool,
+  pub zoom: Zoom,
+  // Center of the view as a fraction of the image width and height
+  pub pan: (f64, f64),
+  // Size of the image at 1:1 once a full size render has been seen
+  pub native_size: Option<(u32, u32)>,
+  // Tiles being shown on top of the image when zoomed in and the ones on
+  // their way
+  pub tiles: Vec<(RequestedImage, DisplayableTile)>,
+  pub tile_requests: Vec<RequestedImage>,
+  pub histogram_log: bool,
+  pub clipping: ClipSettings,
+  // Point of the image under the cursor as fractions of it
+  pub hover: Option<(f64, f64)>,
+  // Clicks on the image pick the white balance instead of panning
+  pub wb_picker: bool,
+  pub compare: Compare,
+  // The render with the default ops to compare the edits against
+  pub before: Option<DisplayableImage>,
+  // Browse the directory by thumbnails instead of the file list
+  pub grid: bool,
+  // Images of the directory being browsed, read again whenever it changes
+  pub listing: Option<(PathBuf, Vec<PathBuf>)>,
+  pub thumbnails: HashMap<String, DisplayableThumbnail>,
+  pub thumbnail_requests: HashSet<String>,
+  // Range of the listing that's on screen in the grid
+  pub thumbnails_visible: (usize, usize),
+  pub filter: directory::Filter,
+  // Directory last selected in the file list and how many files in it pass
+  // the filter
+  pub browsed: PathBuf,
+  pub browsed_count: Option<(PathBuf, directory::Filter, usize)>,
+  pub sort: directory::Sort,
+  pub metadata: directory::MetadataCache,
+  // Told about the browsing order so it gets the right neighbours ready
+  pub prefetcher: std::sync::Arc<Prefetcher>,
 }
 
 impl Chimper {
-  fn new(logoid: conrod_core::image::Id, temp_tint_image_id: conrod_core::image::Id, path: Option<PathBuf>, ui: &mut conrod_core::Ui, export_request_tx: std::sync::mpsc::Sender<RequestedExport>) -> Self {
+  fn new(logoid: conrod_core::image::Id, temp_tint_image_id: conrod_core::image::Id, overlayid: conrod_core::image::Id, path: Option<PathBuf>, ui: &mut conrod_core::Ui, export_request_tx: std::sync::mpsc::Sender<RequestedExport>, export_cancelled: CancelledExports, prefetcher: std::sync::Arc<Prefetcher>, metadata: directory::MetadataCache) -> Self {
     let path = if let Some(path) = path {
       if path.is_absolute() {
         path
@@ -112,26 +204,491 @@ impl Chimper {
       imagepadding: 20.0,
       logoid,
       temp_tint_image_id,
+      overlayid,
       ids: ChimperIds::new(ui.widget_id_generator()),
       file,
-      directory,
+      selection: Vec::new(),
       sideopt: true,
       image: DisplayableState::Empty,
       ops: None,
+      saved_ops: None,
+      edits: HashMap::new(),
+      history: History::new(),
       selected_op: SelectedOp::None,
       fullscreen: false,
       export_request_tx,
+      export_settings: ExportSettings::default(),
+      export_destination: ExportDestination::default(),
+      export_seq: 1,
+      exports: Vec::new(),
+      export_cancelled,
+      next_export_id: 0,
+      export_shared_ops: false,
       crops: None,
+      reload: false,
+      zoom: Zoom::Fit,
+      pan: (0.5, 0.5),
+      native_size: None,
+      tiles: Vec::new(),
+      tile_requests: Vec::new(),
+      histogram_log: false,
+      clipping: ClipSettings::default(),
+      hover: None,
+      wb_picker: false,
+      compare: Compare::Off,
+      before: None,
+      grid: false,
+      listing: None,
+      thumbnails: HashMap::new(),
+      thumbnail_requests: HashSet::new(),
+      thumbnails_visible: (0, 0),
+      filter: directory::Filter::Everything,
+      browsed: directory.clone(),
+      browsed_count: None,
+      sort: directory::Sort::Name,
+      metadata,
+      prefetcher,
+      directory,
+    }
+  }
+
+  /// The size of render needed to show the image at the current zoom level
+  /// in a window of the given size
+  pub fn wanted_size(&self, win_w: u32, win_h: u32) -> (u32, u32) {
+    let shown = match self.image {
+      DisplayableState::Present(ref image) => Some(image),
+      DisplayableState::Requested(_, Some(ref image)) => Some(image),
+      _ => None,
+    };
+    match self.zoom {
+      // Cropping always shows the whole image
+      _ if self.crops.is_some() => (win_w, win_h),
+      Zoom::Fit => (win_w, win_h),
+      Zoom::Fill => {
+        let aspect = shown.map(|i| i.width as f64 / i.height as f64).unwrap_or(1.0);
+        if aspect > win_w as f64 / win_h as f64 {
+          ((win_h as f64 * aspect) as u32, win_h)
+        } else {
+          (win_w, (win_w as f64 / aspect) as u32)
+        }
+      },
+      // Anything bigger than the window gets filled in by tiles
+      Zoom::Scale(scale) => match self.native_size {
+        Some((width, height)) => (
+          ((width as f64 * scale) as u32).min(win_w),
+          ((height as f64 * scale) as u32).min(win_h),
+        ),
+        None => (win_w, win_h),
+      },
     }
   }
+
+  /// The tiles needed to show the visible part of the image in full detail
+  /// when it's zoomed in past the size of a view of the given size
+  pub fn wanted_tiles(&self, view: [f64; 2]) -> Vec<RequestedImage> {
+    let scale = match self.zoom {
+      Zoom::Scale(scale) if self.crops.is_none() => scale,
+      _ => return Vec::new(),
+    };
+    let image = match self.image {
+      DisplayableState::Present(ref image) => image,
+      DisplayableState::Requested(_, Some(ref image)) => image,
+      _ => return Vec::new(),
+    };
+    let file = match self.file {
+      Some(ref file) if *file == image.file => file,
+      _ => return Vec::new(),
+    };
+    let ops = if let Some((ref ops, _)) = self.ops { ops } else { return Vec::new() };
+
+    // Until we've seen a full size tile we don't know how big the image really
+    // is, so go for full size tiles in a fixed grid and fix it up after
+    let (size, shown, grid) = match self.native_size {
+      Some((width, height)) => {
+        let (width, height) = (width as f64 * scale, height as f64 * scale);
+        if width <= view[0] && height <= view[1] {
+          // The whole image fits so there's nothing to fill in
+          return Vec::new()
+        }
+        let tiles = (width.max(height) / TILE_SIZE).ceil() as u32;
+        ((width.ceil() as u32, height.ceil() as u32), (width, height), tiles.next_power_of_two().min(64))
+      },
+      None => {
+        let shown = (image.width as f64 * scale, image.height as f64 * scale);
+        ((u32::MAX, u32::MAX), shown, 4)
+      },
+    };
+
+    // The part of the image in view, as fractions of it
+    let (visx, visy) = ((view[0] / shown.0).min(1.0), (view[1] / shown.1).min(1.0));
+    let (x0, x1) = ((self.pan.0 - visx / 2.0).max(0.0), (self.pan.0 + visx / 2.0).min(1.0));
+    let (y0, y1) = ((self.pan.1 - visy / 2.0).max(0.0), (self.pan.1 + visy / 2.0).min(1.0));
+    let cells = |from: f64, to: f64| {
+      let first = (from * grid as f64).floor() as u32;
+      let last = ((to * grid as f64).ceil() as u32).min(grid).max(first + 1);
+      first..last
+    };
+    let step = REGION_UNITS / grid;
+    let mut tiles = Vec::new();
+    for row in cells(y0, y1) {
+      for col in cells(x0, x1) {
+        tiles.push(RequestedImage {
+          file: file.clone(),
+          width: size.0,
+          height: size.1,
+          ops: Some(ops.clone()),
+          sensor_clipping: false,
+          before: false,
+          thumbnail: false,
+          region: Some(Region {
+            x: col * step,
+            y: row * step,
+            width: step,
+            height: step,
+          }),
+        });
+      }
+    }
+    tiles
+  }
+
+  // Keep track of a tile that came back from the cache
+  fn add_tile(&mut self, tile: DisplayableTile) {
+    if let Some(evicted) = tile.evicted {
+      self.tiles.retain(|(_, t)| t.id != evicted);
+    }
+    let pos = self.tile_requests.iter().position(|req| {
+      req.file == tile.file && req.region == Some(tile.region) && req.ops.as_ref() == Some(&tile.ops)
+    });
+    // Otherwise it's one we no longer want
+    if let Some(pos) = pos {
+      let req = self.tile_requests.remove(pos);
+      if tile.maxwidth == u32::MAX && self.native_size.is_none() {
+        let units = REGION_UNITS as f64;
+        self.native_size = Some((
+          (tile.width as f64 * units / tile.region.width as f64).round() as u32,
+          (tile.height as f64 * units / tile.region.height as f64).round() as u32,
+        ));
+      }
+      self.tiles.push((req, tile));
+    }
+  }
+
+  /// The directory of the image being looked at, or the one we started in
+  /// before an image gets opened
+  pub fn current_dir(&self) -> PathBuf {
+    self.file.as_ref()
+      .and_then(|file| Path::new(file).parent())
+      .filter(|dir| !dir.as_os_str().is_empty())
+      .map(|dir| dir.to_path_buf())
+      .unwrap_or_else(|| self.directory.clone())
+  }
+
+  /// The images of the directory being browsed, which is the one of the image
+  /// being looked at so the grid, stepping and prefetching all go through the
+  /// same files in the same order
+  pub fn images(&mut self) -> &[PathBuf] {
+    let dir = self.current_dir();
+    if self.listing.as_ref().map(|(listdir, _)| *listdir != dir).unwrap_or(true) {
+      let mut images = directory::list_filtered(&dir, self.filter);
+      directory::sort(&mut images, self.sort, &mut self.metadata);
+      self.prefetcher.set_listing(dir.clone(), images.clone());
+      self.listing = Some((dir, images));
+    }
+    &self.listing.as_ref().unwrap().1
+  }
+
+  /// Only show files that pass a different filter
+  pub fn set_filter(&mut self, filter: directory::Filter) {
+    if filter != self.filter {
+      self.filter = filter;
+      self.listing = None;
+    }
+  }
+
+  /// Browse and step through the directory in a different order
+  pub fn set_sort(&mut self, sort: directory::Sort) {
+    if sort != self.sort {
+      self.sort = sort;
+      self.listing = None;
+    }
+  }
+
+  /// How many files in the directory last selected in the file list pass the
+  /// filter
+  pub fn browsed_count(&mut self) -> usize {
+    match self.browsed_count {
+      Some((ref dir, filter, count)) if *dir == self.browsed && filter == self.filter => count,
+      _ => {
+        let count = directory::list_filtered(&self.browsed, self.filter).len();
+        self.browsed_count = Some((self.browsed.clone(), self.filter, count));
+        count
+      },
+    }
+  }
+
+  /// The thumbnails that are on screen but haven't been asked for yet
+  pub fn wanted_thumbnails(&mut self) -> Vec<RequestedImage> {
+    let (first, last) = self.thumbnails_visible;
+    let files: Vec<String> = self.images().get(first..last).unwrap_or(&[]).iter()
+      .filter_map(|path| path.to_str().map(|p| p.to_string()))
+      .collect();
+    files.into_iter()
+      .filter(|file| !self.thumbnails.contains_key(file) && !self.thumbnail_requests.contains(file))
+      .map(|file| {
+        // Show them with any edits, the same way they'd be opened
+        let ops = match self.edits.get(&file) {
+          Some((ops, _)) => Some(ops.clone()),
+          None => sidecar::load_ops(&file),
+        };
+        RequestedImage {
+          file,
+          width: THUMBNAIL_SIZE.0,
+          height: THUMBNAIL_SIZE.1,
+          ops,
+          region: None,
+          sensor_clipping: false,
+          before: false,
+          thumbnail: true,
+        }
+      })
+      .collect()
+  }
+
+  // Move to another image of the directory in browsing order
+  fn step(&mut self, step: Step) {
+    let current = self.file.clone();
+    let images = self.images();
+    if images.is_empty() {
+      return
+    }
+    let pos = current.and_then(|file| images.iter().position(|path| path.to_str() == Some(&file)));
+    let newpos = match (step, pos) {
+      (Step::First, _) | (Step::Next, None) => 0,
+      (Step::Last, _) | (Step::Previous, None) => images.len() - 1,
+      (Step::Next, Some(pos)) => (pos + 1).min(images.len() - 1),
+      (Step::Previous, Some(pos)) => pos.saturating_sub(1),
+    };
+    let file = if let Some(file) = images[newpos].to_str() { file.to_string() } else { return };
+    log::info!("Loading file {:?}", file);
+    self.selection = vec![file.clone()];
+    self.file = Some(file);
+  }
+
+  // The widget the keys are going to, if any
+  fn keyboard_capturer(ui: &conrod_core::Ui) -> Option<&conrod_core::graph::Container> {
+    let id = ui.global_input().current.widget_capturing_keyboard?;
+    ui.widget_graph().widget(id)
+  }
+
+  // Whether text is being typed into a text box
+  fn typing(&self, ui: &conrod_core::Ui) -> bool {
+    Self::keyboard_capturer(ui)
+      .map(|container| container.unique_widget_state::<conrod_core::widget::TextEdit<'static>>().is_some())
+      .unwrap_or(false)
+  }
+
+  // Whether the keys are going to something that uses them for itself, like
+  // text being typed or the browser moving its own selection
+  fn keys_taken(&self, ui: &conrod_core::Ui) -> bool {
+    self.typing(ui) || Self::keyboard_capturer(ui)
+      .map(|container| container.unique_widget_state::<Thumbnails<'static>>().is_some())
+      .unwrap_or(false)
+  }
+
+  // Keep track of a thumbnail that came back from the cache
+  fn add_thumbnail(&mut self, thumbnail: DisplayableThumbnail) {
+    if let Some(evicted) = thumbnail.evicted {
+      self.thumbnails.retain(|_, t| t.id != evicted);
+    }
+    self.thumbnail_requests.remove(&thumbnail.file);
+    self.thumbnails.insert(thumbnail.file.clone(), thumbnail);
+  }
+
+  /// The ops currently being edited, including any crops that are still being
+  /// adjusted in the GUI and haven't been applied to the pipeline yet
+  pub fn edited_ops(&self) -> Option<imagepipe::PipelineOps> {
+    let mut ops = if let Some((ref ops, _)) = self.ops { ops.clone() } else { return None };
+    if let Some(crops) = self.crops {
+      ops.rotatecrop.crop_top = crops.0 as f32;
+      ops.rotatecrop.crop_right = crops.1 as f32;
+      ops.rotatecrop.crop_bottom = crops.2 as f32;
+      ops.rotatecrop.crop_left = crops.3 as f32;
+    }
+    Some(ops)
+  }
+
+  /// The file whose ops are currently loaded in the GUI
+  pub fn image_file(&self) -> Option<&str> {
+    match self.image {
+      DisplayableState::Empty => None,
+      DisplayableState::Requested(ref req, _) => Some(&req.file),
+      DisplayableState::Present(ref disp) => Some(&disp.file),
+      DisplayableState::Broken(ref file, _) => Some(file),
+    }
+  }
+
+  // Keep the edits of the image we're leaving so they can be restored when
+  // the user comes back to it during this session
+  fn stash_edits(&mut self) {
+    let file = if let Some(file) = self.image_file() { file.to_string() } else { return };
+    if let Some(ops) = self.edited_ops() {
+      if let Some((_, ref default_ops)) = self.ops {
+        self.edits.insert(file.clone(), (ops, default_ops.clone()));
+      }
+    }
+    // Get a new thumbnail with whatever was changed
+    self.thumbnails.remove(&file);
+    self.crops = None;
+    self.history.clear();
+    self.zoom = Zoom::Fit;
+    self.pan = (0.5, 0.5);
+    self.native_size = None;
+    self.tiles.clear();
+    self.tile_requests.clear();
+    self.hover = None;
+    self.wb_picker = false;
+    self.before = None;
+  }
+
+  // Replace the ops being edited with ones coming from the history
+  fn apply_ops(&mut self, ops: imagepipe::PipelineOps) {
+    if let Some((ref mut currops, _)) = self.ops {
+      *currops = ops;
+      // Let the crop editing get initialized again from the new ops
+      self.crops = None;
+    }
+  }
+
+  pub fn undo(&mut self) {
+    if let Some(ops) = self.history.undo() {
+      self.apply_ops(ops);
+    }
+  }
+
+  pub fn redo(&mut self) {
+    if let Some(ops) = self.history.redo() {
+      self.apply_ops(ops);
+    }
+  }
+
+  pub fn history_jump(&mut self, pos: usize) {
+    if let Some(ops) = self.history.jump(pos) {
+      self.apply_ops(ops);
+    }
+  }
+
+  /// Queue an export of a file with the current export settings
+  pub fn export(&mut self, file: String, ops: Option<imagepipe::PipelineOps>) {
+    let id = self.next_export_id;
+    self.next_export_id += 1;
+    let seq = self.export_seq;
+    self.export_seq += 1;
+    self.exports.push(ExportStatus {
+      id,
+      file: file.clone(),
+      state: ExportState::Queued,
+    });
+    self.export_request_tx.send(RequestedExport {
+      id,
+      file,
+      ops,
+      settings: self.export_settings,
+      destination: self.export_destination.clone(),
+      seq,
+    }).unwrap();
+  }
+
+  /// Queue an export of several files, either each with its own edits or
+  /// all with the ones of the image currently being edited
+  pub fn export_batch(&mut self, files: Vec<String>) {
+    let shared = if self.export_shared_ops { self.edited_ops() } else { None };
+    for file in files {
+      let ops = if self.export_shared_ops {
+        shared.clone()
+      } else if self.file.as_ref() == Some(&file) && self.ops.is_some() {
+        self.edited_ops()
+      } else if let Some((ops, _)) = self.edits.get(&file) {
+        Some(ops.clone())
+      } else {
+        sidecar::load_ops(&file)
+      };
+      self.export(file, ops);
+    }
+  }
+
+  /// Queue an export of every image in the directory being browsed
+  pub fn export_directory(&mut self) {
+    let files = directory::list_images(&self.current_dir()).iter()
+      .filter_map(|path| path.to_str().map(|p| p.to_string()))
+      .collect();
+    self.export_batch(files);
+  }
+
+  /// Ask for a queued export to be dropped. A worker may already have picked
+  /// it up so it's only marked as cancelled once one confirms it, otherwise it
+  /// just runs to the end.
+  pub fn cancel_export(&mut self, id: u64) {
+    if self.exports.iter().any(|job| job.id == id && job.state == ExportState::Queued) {
+      self.export_cancelled.lock().unwrap().insert(id);
+    }
+  }
+
+  fn update_export(&mut self, status: ExportStatus) {
+    if let Some(job) = self.exports.iter_mut().find(|job| job.id == status.id) {
+      job.state = status.state;
+    } else {
+      self.exports.push(status);
+    }
+  }
+
+  // Write the current ops to the sidecar of the current file if they changed
+  fn save_sidecar(&mut self) {
+    let file = if let Some(ref file) = self.file { file.clone() } else { return };
+    let ops = if let Some(ops) = self.edited_ops() { ops } else { return };
+    if self.saved_ops.as_ref() == Some(&ops) {
+      return
+    }
+    if self.saved_ops.is_none() {
+      if let Some((_, ref default_ops)) = self.ops {
+        if ops == *default_ops {
+          // Don't litter the directory with sidecars for unedited images
+          return
+        }
+      }
+    }
+    if let Err(e) = sidecar::save_ops(&file, &ops) {
+      log::error!("Error saving sidecar for \"{}\": {}", file, e);
+    }
+    // Even on error mark them as saved so we don't retry on every frame
+    self.saved_ops = Some(ops);
+  }
 }
 
 static WIN_W: f64 = 1200.0;
 static WIN_H: f64 = 800.0;
+// Most tile textures to keep around at once
+const TILE_TEXTURES: usize = 64;
+// Roughly how big a tile should be on screen
+static TILE_SIZE: f64 = 512.0;
+// Most thumbnail textures to keep around at once and how big they get
+const THUMBNAIL_TEXTURES: usize = 256;
+static THUMBNAIL_TEXTURE_SIZE: f64 = 256.0;
+
+#[derive(Debug, Copy, Clone)]
+enum Step {
+  Previous,
+  Next,
+  First,
+  Last,
+}
 
 enum AppEvent {
   Fullscreen(bool),
   Sidepane,
+  Undo,
+  Redo,
+  Step(Step),
 }
 
 pub fn run_app(path: Option<PathBuf>) {
@@ -159,6 +716,9 @@ pub fn run_app(path: Option<PathBuf>) {
   let texture = glium::texture::SrgbTexture2d::new(&display, raw).unwrap();
   let temp_tint_image_id = image_map.insert(texture);
 
+  // The clipping overlay gets drawn into this once there's an image to mark
+  let overlayid = image_map.insert(blank_texture(&display));
+
   // A channel to send events from the main `winit` thread to the conrod thread.
   let (event_tx, event_rx) = std::sync::mpsc::channel();
   // A channel to send app events from the main `winit` thread to the conrod thread.
@@ -171,48 +731,177 @@ pub fn run_app(path: Option<PathBuf>) {
   let (image_request_tx, image_request_rx) = std::sync::mpsc::channel();
   // A channel to request images from the export thread
   let (export_request_tx, export_request_rx) = std::sync::mpsc::channel();
+  // A channel to receive export progress from the export thread
+  let (export_status_tx, export_status_rx) = std::sync::mpsc::channel();
+  // Exports that were cancelled while still in the queue
+  let export_cancelled = CancelledExports::default();
+  let export_cancelled2 = export_cancelled.clone();
   // A channel to receive images from the cache thread
   let (image_result_tx, image_result_rx) = std::sync::mpsc::channel();
+  // The image cache is shared with the file watcher so it can throw away
+  // anything from files that changed, and with the exports so it knows which
+  // of those changes were our own
+  let cache = std::sync::Arc::new(ImageCache::new());
+  let cache2 = cache.clone();
+  let cache3 = cache.clone();
+  // Gets the files around the one being looked at ready, in the order the GUI
+  // browses them
+  let prefetcher = Prefetcher::new(cache.clone());
+  let prefetcher2 = prefetcher.clone();
   // A channel to send images from the main thread to the conrod thread
   let (image_displayable_tx, image_displayable_rx) = std::sync::mpsc::channel();
+  // A channel to send the clipping overlay settings from the conrod thread to the main thread
+  let (clip_settings_tx, clip_settings_rx) = std::sync::mpsc::channel();
+  // A channel to tell the main thread which thumbnails the grid is showing so it keeps those textures
+  let (thumbnails_shown_tx, thumbnails_shown_rx) = std::sync::mpsc::channel();
+  // A channel to send tiles of zoomed in views from the main thread to the conrod thread
+  let (tile_displayable_tx, tile_displayable_rx) = std::sync::mpsc::channel();
+  // A channel to send the unedited render to compare against from the main thread to the conrod thread
+  let (before_displayable_tx, before_displayable_rx) = std::sync::mpsc::channel();
+  // A channel to send thumbnails for the browser from the main thread to the conrod thread
+  let (thumbnail_displayable_tx, thumbnail_displayable_rx) = std::sync::mpsc::channel();
   // Clone the handle to the events loop so that we can interrupt it when we have a new image
   let events_loop_proxy2 = event_loop.create_proxy();
+  let events_loop_proxy3 = event_loop.create_proxy();
 
   // A function that runs the conrod loop.
   fn run_conrod(
     event_rx: std::sync::mpsc::Receiver<conrod_core::event::Input>,
     app_event_rx: std::sync::mpsc::Receiver<AppEvent>,
     image_displayable_rx: std::sync::mpsc::Receiver<DisplayableState>,
+    tile_displayable_rx: std::sync::mpsc::Receiver<DisplayableTile>,
+    before_displayable_rx: std::sync::mpsc::Receiver<DisplayableImage>,
+    thumbnail_displayable_rx: std::sync::mpsc::Receiver<DisplayableThumbnail>,
     image_request_tx: std::sync::mpsc::Sender<RequestedImage>,
     export_request_tx: std::sync::mpsc::Sender<RequestedExport>,
+    export_status_rx: std::sync::mpsc::Receiver<ExportStatus>,
+    export_cancelled: CancelledExports,
     render_tx: std::sync::mpsc::Sender<conrod_core::render::OwnedPrimitives>,
     events_loop_proxy: glium::glutin::event_loop::EventLoopProxy<()>,
+    cache: std::sync::Arc<ImageCache>,
     logoid: conrod_core::image::Id,
     temp_tint_image_id: conrod_core::image::Id,
+    overlayid: conrod_core::image::Id,
+    clip_settings_tx: std::sync::mpsc::Sender<ClipSettings>,
+    thumbnails_shown_tx: std::sync::mpsc::Sender<Vec<String>>,
+    prefetcher: std::sync::Arc<Prefetcher>,
     path: Option<PathBuf>,
   ) {
     // Construct our `Ui`.
     let mut ui = conrod_core::UiBuilder::new([WIN_W, WIN_H]).build();
     ui.fonts.insert(Font::from_bytes(include_bytes!("../../fonts/NotoSans-Regular.ttf")).unwrap());
 
-    let mut chimp = Chimper::new(logoid, temp_tint_image_id, path, &mut ui, export_request_tx);
+    // Metadata for sorting gets read in the background, sort again once it's in
+    let (metadata_read_tx, metadata_read_rx) = std::sync::mpsc::channel();
+    let metadata_proxy = events_loop_proxy.clone();
+    let metadata = directory::MetadataCache::new(move || {
+      if metadata_read_tx.send(()).is_ok() {
+        let _ = metadata_proxy.send_event(());
+      }
+    });
+
+    let mut chimp = Chimper::new(logoid, temp_tint_image_id, overlayid, path, &mut ui, export_request_tx, export_cancelled, prefetcher, metadata);
+
+    // Watch for files changing under us, waking up the loop to deal with it
+    let (file_changed_tx, file_changed_rx) = std::sync::mpsc::channel();
+    let watcher_proxy = events_loop_proxy.clone();
+    let mut watcher = DirWatcher::new(move |path: PathBuf| {
+      if let Some(file) = path.to_str() {
+        cache.invalidate(file);
+      }
+      if file_changed_tx.send(path).is_ok() {
+        let _ = watcher_proxy.send_event(());
+      }
+    });
 
     // Many widgets require another frame to finish drawing after clicks or hovers, so we
     // insert an update into the conrod loop using this `bool` after each event.
     let mut needs_update = true;
+    // What the main thread last heard about the clipping overlay
+    let mut sent_clipping = chimp.clipping;
+    // What the main thread last heard about the thumbnails in the grid
+    let mut sent_shown: Vec<String> = Vec::new();
+    // Whether the last image request came with a sensor clipping mask
+    let mut sensor_requested = false;
+    // The last request for an unedited render to compare against
+    let mut before_requested: Option<RequestedImage> = None;
     'conrod: loop {
       // Process any app events
       while let Ok(event) = app_event_rx.try_recv() {
         match event {
           AppEvent::Fullscreen(fs) => chimp.fullscreen = fs,
           AppEvent::Sidepane => chimp.use_sidepane = !chimp.use_sidepane,
+          // Ctrl+Z while typing is meant for the text, not the edits. The grid
+          // leaves these keys alone so they still work while browsing.
+          AppEvent::Undo => {
+            if !chimp.typing(&ui) {
+              chimp.undo();
+            }
+          },
+          AppEvent::Redo => {
+            if !chimp.typing(&ui) {
+              chimp.redo();
+            }
+          },
+          AppEvent::Step(step) => {
+            if !chimp.keys_taken(&ui) {
+              chimp.step(step);
+            }
+          },
         }
       }
 
+      // Receive any export progress
+      while let Ok(status) = export_status_rx.try_recv() {
+        chimp.update_export(status);
+      }
+
       // Receive any images
       while let Ok(image) = image_displayable_rx.try_recv() {
+        if let DisplayableState::Present(ref disp) = image {
+          if disp.maxwidth == u32::MAX {
+            // A full size render so now we know what 1:1 means
+            chimp.native_size = Some((disp.width, disp.height));
+          }
+        }
         chimp.image = image;
       }
+      while let Ok(tile) = tile_displayable_rx.try_recv() {
+        chimp.add_tile(tile);
+      }
+      while let Ok(thumbnail) = thumbnail_displayable_rx.try_recv() {
+        chimp.add_thumbnail(thumbnail);
+      }
+      while let Ok(before) = before_displayable_rx.try_recv() {
+        if chimp.file.as_ref() == Some(&before.file) {
+          chimp.before = Some(before);
+        }
+      }
+
+      if metadata_read_rx.try_iter().count() > 0 && chimp.sort.uses_metadata() {
+        chimp.listing = None;
+      }
+
+      // Check if the image on screen was changed by someone else
+      while let Ok(path) = file_changed_rx.try_recv() {
+        if chimp.file.as_ref().map(|f| PathBuf::from(f) == path).unwrap_or(false) {
+          chimp.reload = true;
+        }
+        if chimp.listing.as_ref().map(|(dir, _)| path.parent() == Some(dir.as_path())).unwrap_or(false) {
+          chimp.listing = None;
+        }
+        if path.parent() == Some(chimp.browsed.as_path()) {
+          chimp.browsed_count = None;
+        }
+        if let Some(file) = path.to_str() {
+          chimp.thumbnails.remove(file);
+          chimp.thumbnail_requests.remove(file);
+        }
+      }
+      watcher.watch(&chimp.current_dir());
+      // Also tells the prefetcher about the new order whenever it changes,
+      // even when nothing else on screen needs the listing
+      chimp.images();
 
       // Collect any pending events.
       let mut events = Vec::new();
@@ -235,6 +924,11 @@ pub fn run_app(path: Option<PathBuf>) {
         needs_update = true;
       }
 
+      if chimp.file.is_some() && chimp.file.as_deref() != chimp.image_file() {
+        chimp.stash_edits();
+      }
+
+      let wanted = chimp.wanted_size(ui.win_w as u32, ui.win_h as u32);
       if let Some(ref file) = chimp.file {
         let mut need_new_image = false;
         let mut new_file = false;
@@ -262,11 +956,11 @@ pub fn run_app(path: Option<PathBuf>) {
             } else {
               chimp.ops = Some((disp.ops.clone(), disp.default_ops.clone()));
             }
-            if ui.win_w as u32 > disp.maxwidth || ui.win_h  as u32 > disp.maxheight {
+            if wanted.0 > disp.maxwidth || wanted.1 > disp.maxheight {
               need_new_image = true;
             }
           },
-          DisplayableState::Broken(ref bfile) => {
+          DisplayableState::Broken(ref bfile, _) => {
             if bfile != file {
               need_new_image = true;
               new_file = true;
@@ -275,20 +969,53 @@ pub fn run_app(path: Option<PathBuf>) {
           },
         }
 
+        // The sensor clipping mask only gets asked for along with the image
+        let want_sensor = chimp.clipping.enabled && chimp.clipping.sensor;
+        if want_sensor && !sensor_requested {
+          need_new_image = true;
+        }
+
+        if chimp.reload {
+          need_new_image = true;
+          chimp.reload = false;
+          chimp.tiles.clear();
+          chimp.tile_requests.clear();
+          before_requested = None;
+        }
+
         if need_new_image {
           // We have a new image so we need to request it
           let reqops = if let Some((ref ops, _)) = chimp.ops {
             Some(ops.clone())
           } else {
-            None
+            chimp.saved_ops = sidecar::load_ops(file);
+            if let Some(ops) = chimp.edits.get(file) {
+              // We've been here before in this session so restore those edits
+              chimp.ops = Some(ops.clone());
+              Some(ops.0.clone())
+            } else {
+              // Otherwise pick up any edits saved to disk
+              chimp.saved_ops.clone()
+            }
           };
           let req = RequestedImage {
             file: file.clone(),
-            width: ui.win_w as u32,
-            height: ui.win_h as u32,
+            width: wanted.0,
+            height: wanted.1,
             ops: reqops,
+            region: None,
+            sensor_clipping: false,
+            before: false,
+            thumbnail: false,
           };
           image_request_tx.send(req.clone()).unwrap();
+          sensor_requested = want_sensor;
+          if want_sensor && directory::is_raw(std::path::Path::new(file)) {
+            image_request_tx.send(RequestedImage {
+              sensor_clipping: true,
+              ..req.clone()
+            }).unwrap();
+          }
           let image = match (new_file, chimp.image) {
             (false, DisplayableState::Present(image)) => Some(image),
             (false, DisplayableState::Requested(_, Some(image))) => Some(image),
@@ -296,12 +1023,95 @@ pub fn run_app(path: Option<PathBuf>) {
           };
           chimp.image = DisplayableState::Requested(req, image);
         }
+
+        // Comparing needs the same file at the same size with the default ops
+        let size = match chimp.image {
+          DisplayableState::Present(ref disp) => Some((disp.maxwidth, disp.maxheight)),
+          DisplayableState::Requested(ref req, _) => Some((req.width, req.height)),
+          _ => None,
+        };
+        if let (true, Some((width, height))) = (chimp.compare != Compare::Off, size) {
+          let req = RequestedImage {
+            file: file.clone(),
+            width,
+            height,
+            ops: None,
+            region: None,
+            sensor_clipping: false,
+            before: true,
+            thumbnail: false,
+          };
+          if before_requested.as_ref() != Some(&req) {
+            image_request_tx.send(req.clone()).unwrap();
+            before_requested = Some(req);
+          }
+        }
       } else {
         chimp.image = DisplayableState::Empty;
       }
 
+      // Ask for whatever tiles the view is now missing
+      let padding = if chimp.use_sidepane { chimp.imagepadding } else { 0.0 };
+      if let (Some(w), Some(h)) = (ui.w_of(chimp.ids.imgcanvas), ui.h_of(chimp.ids.imgcanvas)) {
+        // Side by side the edited image only gets half the view
+        let w = if chimp.compare == Compare::SideBySide { (w - padding) / 2.0 + padding } else { w };
+        let wanted_tiles = chimp.wanted_tiles([w - padding, h - padding]);
+        chimp.tiles.retain(|(req, _)| wanted_tiles.contains(req));
+        chimp.tile_requests.retain(|req| wanted_tiles.contains(req));
+        for req in wanted_tiles {
+          if !chimp.tiles.iter().any(|(r, _)| *r == req) && !chimp.tile_requests.contains(&req) {
+            image_request_tx.send(req.clone()).unwrap();
+            chimp.tile_requests.push(req);
+          }
+        }
+      }
+
+      // Fill in the thumbnails of the browser
+      if chimp.grid && chimp.sideopt && chimp.use_sidepane {
+        for req in chimp.wanted_thumbnails() {
+          chimp.thumbnail_requests.insert(req.file.clone());
+          image_request_tx.send(req).unwrap();
+        }
+      }
+
+      // Persist any edits to the image being shown
+      if let DisplayableState::Present(_) = chimp.image {
+        chimp.save_sidecar();
+      }
+
       // Instantiate a GUI demonstrating every widget type provided by conrod.
       gui::draw_gui(&mut chimp, &mut ui);
+
+      // The overlay gets drawn on the main thread so let it know what changed
+      if chimp.clipping != sent_clipping {
+        sent_clipping = chimp.clipping;
+        if clip_settings_tx.send(sent_clipping).is_err() || events_loop_proxy.send_event(()).is_err() {
+          break 'conrod;
+        }
+      }
+      // The thumbnails on screen are the last ones whose textures should go
+      if chimp.grid && chimp.sideopt && chimp.use_sidepane {
+        let (first, last) = chimp.thumbnails_visible;
+        let visible: Vec<String> = chimp.images().get(first..last).unwrap_or(&[]).iter()
+          .filter_map(|path| path.to_str().map(|p| p.to_string()))
+          .collect();
+        let shown: Vec<String> = visible.into_iter()
+          .filter(|file| chimp.thumbnails.contains_key(file))
+          .collect();
+        if shown != sent_shown {
+          sent_shown = shown.clone();
+          if thumbnails_shown_tx.send(shown).is_err() || events_loop_proxy.send_event(()).is_err() {
+            break 'conrod;
+          }
+        }
+      }
+
+      // Record any edits in the history, while the mouse is down we're in the
+      // middle of a drag so wait for it to finish to get a single undo step
+      if let Some(ops) = chimp.edited_ops() {
+        let dragging = ui.global_input().current.mouse.buttons.left().is_down();
+        chimp.history.update(&ops, dragging);
+      }
       //conrod_example_shared::gui(&mut ui.set_widgets(), &ids, &mut app);
 
       // Render the `Ui` to a list of primitives that we can send to the main thread for
@@ -317,52 +1127,201 @@ pub fn run_app(path: Option<PathBuf>) {
     }
   }
 
+  // Tiles only make sense for the view they were asked for so a tile for a
+  // different file, size or ops replaces all the others. Sensor clipping masks
+  // and unedited renders to compare against go in the same queue as they're
+  // also extras for the whole image, just ones that go first. Thumbnails are
+  // for other files so they have their own queue.
+  fn queue_request(
+    req: RequestedImage,
+    latest: &mut Option<RequestedImage>,
+    tiles: &mut VecDeque<RequestedImage>,
+    thumbnails: &mut VecDeque<RequestedImage>,
+  ) {
+    if req.thumbnail {
+      if !thumbnails.contains(&req) {
+        thumbnails.push_back(req);
+      }
+    } else if req.sensor_clipping {
+      tiles.retain(|tile| !tile.sensor_clipping);
+      tiles.push_front(req);
+    } else if req.before {
+      tiles.retain(|tile| !tile.before);
+      tiles.push_front(req);
+    } else if req.region.is_none() {
+      tiles.retain(|tile| tile.file == req.file);
+      *latest = Some(req);
+    } else {
+      tiles.retain(|tile| {
+        tile.sensor_clipping || tile.before || (
+          tile.file == req.file && tile.width == req.width && tile.height == req.height && tile.ops == req.ops
+        )
+      });
+      if !tiles.contains(&req) {
+        tiles.push_back(req);
+      }
+    }
+  }
+
   fn run_cache(
     image_request_rx: std::sync::mpsc::Receiver<RequestedImage>,
     image_result_tx: std::sync::mpsc::Sender<ImageResult>,
     events_loop_proxy: glium::glutin::event_loop::EventLoopProxy<()>,
+    cache: std::sync::Arc<ImageCache>,
+    prefetcher: std::sync::Arc<Prefetcher>,
   ) {
-    let cache = ImageCache::new();
+    let mut latest: Option<RequestedImage> = None;
+    let mut tiles: VecDeque<RequestedImage> = VecDeque::new();
+    let mut thumbnails: VecDeque<RequestedImage> = VecDeque::new();
     'cache: loop {
-      // Block until we either get a request or the other end closes and we'
-      let mut req = match image_request_rx.recv() {
-        Err(_) => break 'cache,
-        Ok(req) => req,
-      };
+      // Block until we either get a request or the other end closes and we're
+      // done, unless there's still work left from before
+      if latest.is_none() && tiles.is_empty() && thumbnails.is_empty() {
+        match image_request_rx.recv() {
+          Err(_) => break 'cache,
+          Ok(req) => queue_request(req, &mut latest, &mut tiles, &mut thumbnails),
+        }
+      }
 
-      // Only process the latest request, drop all others
+      // Only process the latest request of the whole image, drop all others
       // If all the requests were buffered and the other end disconnected
       // then we're done
       'recv: loop {
         match image_request_rx.try_recv() {
           Err(TryRecvError::Empty) => break 'recv,
           Err(TryRecvError::Disconnected) => break 'cache,
-          Ok(r) => {req = r;},
+          Ok(req) => queue_request(req, &mut latest, &mut tiles, &mut thumbnails),
         }
       }
 
+      // The whole image goes first, then one tile at a time so that new
+      // requests get a chance to replace the rest, then the thumbnails with
+      // the last ones asked for first as those are the ones on screen
+      let (req, is_extra) = if let Some(req) = latest.take() {
+        // Real requests always go first so stop any prefetching that hasn't
+        // started yet
+        prefetcher.cancel();
+        (req, false)
+      } else if let Some(req) = tiles.pop_front() {
+        (req, true)
+      } else if let Some(req) = thumbnails.pop_back() {
+        (req, true)
+      } else {
+        continue 'cache
+      };
+
       // Grab the image from the cache
-      let res = cache.get(req);
+      let res = cache.get(req.clone());
       if image_result_tx.send(res).is_err() || events_loop_proxy.send_event(()).is_err() {
         // If we can't send we're also done as there's no one to receive anymore
         break 'cache
       }
+
+      // While the user looks at this one get the neighbours ready
+      if !is_extra {
+        prefetcher.prefetch_around(&req);
+      }
     }
+    prefetcher.close();
   }
 
   fn run_export(
     export_request_rx: std::sync::mpsc::Receiver<RequestedExport>,
+    export_status_tx: std::sync::mpsc::Sender<ExportStatus>,
+    export_cancelled: CancelledExports,
+    events_loop_proxy: glium::glutin::event_loop::EventLoopProxy<()>,
+    cache: std::sync::Arc<ImageCache>,
   ) {
-    'export: loop {
-      // Block until we either get a request or the other end closes and we'
-      let req = match export_request_rx.recv() {
-        Err(_) => break 'export,
-        Ok(req) => req,
-      };
+    let report = move |status: ExportStatus| {
+      if let ExportState::Done(ref path) = status.state {
+        if let Some(file) = path.to_str() {
+          cache.exported(file);
+        }
+      }
+      // If the GUI is gone there's no one to tell anymore
+      if export_status_tx.send(status).is_ok() {
+        let _ = events_loop_proxy.send_event(());
+      }
+    };
+
+    let queue = ExportQueue::new();
+    let workers = spawn_workers(&queue, default_workers(), export_cancelled, report.clone());
 
-      // Do the export itself
-      export_file(&req);
+    // Hand every request over to the workers as soon as it comes in so that
+    // queued jobs can be cancelled from the GUI
+    while let Ok(req) = export_request_rx.recv() {
+      report(ExportStatus {
+        id: req.id,
+        file: req.file.clone(),
+        state: ExportState::Queued,
+      });
+      queue.push(req);
     }
+
+    queue.close();
+    for worker in workers {
+      let _ = worker.join();
+    }
+  }
+
+  // Turns a render into something the GPU can draw
+  fn upload(display: &glium::Display, image: &imagepipe::SRGBImage) -> SrgbTexture2d {
+    let dims = (image.width as u32, image.height as u32);
+    let raw_image = glium::texture::RawImage2d::from_raw_rgb_reversed(&image.data, dims);
+    glium::texture::SrgbTexture2d::with_format(
+      display,
+      raw_image,
+      glium::texture::SrgbFormat::U8U8U8,
+      glium::texture::MipmapsOption::NoMipmap
+    ).unwrap()
+  }
+
+  // Thumbnails get shrunk some more as there can be a lot of them around
+  fn upload_thumbnail(display: &glium::Display, image: &imagepipe::SRGBImage) -> (SrgbTexture2d, (u32, u32)) {
+    let scale = (THUMBNAIL_TEXTURE_SIZE / image.width.max(image.height) as f64).min(1.0);
+    let dims = (
+      ((image.width as f64 * scale).round() as u32).max(1),
+      ((image.height as f64 * scale).round() as u32).max(1),
+    );
+    let full = image::RgbImage::from_raw(image.width as u32, image.height as u32, image.data.clone()).unwrap();
+    let small = image::imageops::thumbnail(&full, dims.0, dims.1);
+    let raw_image = glium::texture::RawImage2d::from_raw_rgb_reversed(&small.into_raw(), dims);
+    let texture = glium::texture::SrgbTexture2d::with_format(
+      display,
+      raw_image,
+      glium::texture::SrgbFormat::U8U8U8,
+      glium::texture::MipmapsOption::NoMipmap
+    ).unwrap();
+    (texture, dims)
+  }
+
+  // Something to stand in for a texture that has nothing to show yet
+  fn blank_texture(display: &glium::Display) -> SrgbTexture2d {
+    let raw = glium::texture::RawImage2d::from_raw_rgba_reversed(&[0, 0, 0, 0], (1, 1));
+    glium::texture::SrgbTexture2d::new(display, raw).unwrap()
+  }
+
+  // Redraw the clipping overlay for the image being shown
+  fn update_overlay(
+    display: &glium::Display,
+    image_map: &mut conrod_core::image::Map<SrgbTexture2d>,
+    overlayid: conrod_core::image::Id,
+    shown: &Option<(String, std::sync::Arc<ImageOutput>)>,
+    sensor: &Option<(String, std::sync::Arc<ImageOutput>)>,
+    settings: &ClipSettings,
+  ) {
+    let (file, image) = match shown {
+      Some((file, image)) if settings.enabled => (file, image),
+      _ => return,
+    };
+    let sensor = match sensor {
+      Some((sfile, sensor)) if settings.sensor && sfile == file => Some(&sensor.image),
+      _ => None,
+    };
+    let data = overlay::clipping(&image.image, sensor, settings);
+    let dims = (image.image.width as u32, image.image.height as u32);
+    let raw = glium::texture::RawImage2d::from_raw_rgba_reversed(&data, dims);
+    image_map.replace(overlayid, glium::texture::SrgbTexture2d::new(display, raw).unwrap());
   }
 
   // Draws the given `primitives` to the given `Display`.
@@ -384,12 +1343,22 @@ pub fn run_app(path: Option<PathBuf>) {
     event_rx,
     app_event_rx,
     image_displayable_rx,
+    tile_displayable_rx,
+    before_displayable_rx,
+    thumbnail_displayable_rx,
     image_request_tx,
     export_request_tx,
+    export_status_rx,
+    export_cancelled,
     render_tx,
     events_loop_proxy,
+    cache,
     logoid,
     temp_tint_image_id,
+    overlayid,
+    clip_settings_tx,
+    thumbnails_shown_tx,
+    prefetcher,
     path
   ));
 
@@ -398,16 +1367,35 @@ pub fn run_app(path: Option<PathBuf>) {
     image_request_rx,
     image_result_tx,
     events_loop_proxy2,
+    cache2,
+    prefetcher2,
   ));
 
   // Spawn the export loop on its own thread.
-  std::thread::spawn(move || run_export(export_request_rx));
+  std::thread::spawn(move || run_export(
+    export_request_rx,
+    export_status_tx,
+    export_cancelled2,
+    events_loop_proxy3,
+    cache3,
+  ));
 
   // Run the `winit` loop.
   let mut is_waken = false;
   let mut latest_primitives = None;
   let mut fullscreen = false;
+  let mut modifiers = glium::glutin::event::ModifiersState::empty();
   let mut imageid = None;
+  let mut beforeid = None;
+  // Textures of the tiles sent to the GUI, oldest first
+  let mut tile_ids = VecDeque::new();
+  // Textures of the thumbnails sent to the GUI and their files, least
+  // recently shown first
+  let mut thumbnail_ids: VecDeque<(String, conrod_core::image::Id)> = VecDeque::new();
+  // What the clipping overlay gets drawn from
+  let mut shown_image = None;
+  let mut sensor_image = None;
+  let mut clip_settings = ClipSettings::default();
   support::run_loop(display, event_loop, move |request, display| {
     match request {
       support::Request::Event {
@@ -461,7 +1449,26 @@ pub fn run_app(path: Option<PathBuf>) {
               }
               app_event_tx.send(AppEvent::Fullscreen(fullscreen)).unwrap();
             },
-            // Fullscreen on F11
+            glium::glutin::event::WindowEvent::ModifiersChanged(state) => {
+              modifiers = *state;
+            },
+            // Undo on Ctrl+Z and redo on Ctrl+Shift+Z
+            glium::glutin::event::WindowEvent::KeyboardInput {
+              input:
+                glium::glutin::event::KeyboardInput {
+                  virtual_keycode: Some(glium::glutin::event::VirtualKeyCode::Z),
+                  state: glium::glutin::event::ElementState::Pressed,
+                  ..
+                },
+              ..
+            } if modifiers.ctrl() => {
+              if modifiers.shift() {
+                app_event_tx.send(AppEvent::Redo).unwrap();
+              } else {
+                app_event_tx.send(AppEvent::Undo).unwrap();
+              }
+            },
+            // Sidepane on Tab
             glium::glutin::event::WindowEvent::KeyboardInput {
               input:
                 glium::glutin::event::KeyboardInput {
@@ -473,49 +1480,164 @@ pub fn run_app(path: Option<PathBuf>) {
             } => {
               app_event_tx.send(AppEvent::Sidepane).unwrap();
             },
+            // Step through the images of the directory
+            glium::glutin::event::WindowEvent::KeyboardInput {
+              input:
+                glium::glutin::event::KeyboardInput {
+                  virtual_keycode: Some(key),
+                  state: glium::glutin::event::ElementState::Pressed,
+                  ..
+                },
+              ..
+            } => {
+              use glium::glutin::event::VirtualKeyCode;
+              let step = match key {
+                VirtualKeyCode::Left | VirtualKeyCode::Back => Some(Step::Previous),
+                VirtualKeyCode::Right | VirtualKeyCode::Space => Some(Step::Next),
+                VirtualKeyCode::Home => Some(Step::First),
+                VirtualKeyCode::End => Some(Step::Last),
+                _ => None,
+              };
+              if let Some(step) = step {
+                app_event_tx.send(AppEvent::Step(step)).unwrap();
+              }
+            },
             _ => {}
           },
           glium::glutin::event::Event::UserEvent(()) => {
             // If we have a new image insert it into the map so it can be displayed
             // and then send a message to the GUI thread to display it
+            let mut overlay_changed = false;
+            while let Ok(settings) = clip_settings_rx.try_recv() {
+              clip_settings = settings;
+              overlay_changed = true;
+            }
+            for shown in thumbnails_shown_rx.try_iter() {
+              for file in shown {
+                if let Some(pos) = thumbnail_ids.iter().position(|(f, _)| *f == file) {
+                  let entry = thumbnail_ids.remove(pos).unwrap();
+                  thumbnail_ids.push_back(entry);
+                }
+              }
+            }
             if let Ok(image_result) = image_result_rx.try_recv() {
-              let displayable = if let Some(image) = image_result.image {
-                // Create a new image
-                let width = image.image.width as u32;
-                let height = image.image.height as u32;
-                let maxwidth = image.maxwidth as u32;
-                let maxheight = image.maxheight as u32;
-                let dims = (width, height);
-                let data = &image.image.data;
-                let raw_image = glium::texture::RawImage2d::from_raw_rgb_reversed(data, dims);
-                let img = glium::texture::SrgbTexture2d::with_format(
-                  display,
-                  raw_image,
-                  glium::texture::SrgbFormat::U8U8U8,
-                  glium::texture::MipmapsOption::NoMipmap
-                ).unwrap();
-                let id = if let Some(currid) = imageid {
-                  image_map.replace(currid, img);
-                  currid
-                } else {
-                  let newid = image_map.insert(img);
-                  imageid = Some(newid);
-                  newid
-                };
-                DisplayableState::Present(DisplayableImage {
-                  file: image_result.file,
-                  id,
-                  width,
-                  height,
-                  maxwidth,
-                  maxheight,
-                  ops: image.ops.clone(),
-                  default_ops: image.default_ops.clone(),
-                })
+              if image_result.sensor_clipping {
+                if let Ok(image) = image_result.image {
+                  sensor_image = Some((image_result.file, image));
+                  overlay_changed = true;
+                }
+              } else if image_result.thumbnail {
+                // A thumbnail that failed just stays blank in the browser
+                if let Ok(image) = image_result.image {
+                  let (texture, (width, height)) = upload_thumbnail(display, &image.image);
+                  let file = image_result.file;
+                  let mut evicted = None;
+                  let id = if let Some(pos) = thumbnail_ids.iter().position(|(f, _)| *f == file) {
+                    let (_, id) = thumbnail_ids.remove(pos).unwrap();
+                    image_map.replace(id, texture);
+                    id
+                  } else {
+                    if thumbnail_ids.len() >= THUMBNAIL_TEXTURES {
+                      evicted = thumbnail_ids.pop_front().map(|(_, old)| {
+                        image_map.remove(old);
+                        old
+                      });
+                    }
+                    image_map.insert(texture)
+                  };
+                  thumbnail_ids.push_back((file.clone(), id));
+                  thumbnail_displayable_tx.send(DisplayableThumbnail {
+                    file,
+                    id,
+                    width,
+                    height,
+                    evicted,
+                  }).unwrap();
+                }
+              } else if image_result.before {
+                // Without it there's just nothing to compare against
+                if let Ok(image) = image_result.image {
+                  let img = upload(display, &image.image);
+                  let id = if let Some(currid) = beforeid {
+                    image_map.replace(currid, img);
+                    currid
+                  } else {
+                    let newid = image_map.insert(img);
+                    beforeid = Some(newid);
+                    newid
+                  };
+                  before_displayable_tx.send(DisplayableImage {
+                    file: image_result.file,
+                    id,
+                    width: image.image.width as u32,
+                    height: image.image.height as u32,
+                    maxwidth: image.maxwidth,
+                    maxheight: image.maxheight,
+                    ops: image.ops.clone(),
+                    default_ops: image.default_ops.clone(),
+                    output: image.clone(),
+                  }).unwrap();
+                }
+              } else if let Some(region) = image_result.region {
+                // Tiles of a zoomed in view each get their own texture, a tile
+                // that failed just leaves the whole image showing under it
+                if let Ok(image) = image_result.image {
+                  let id = image_map.insert(upload(display, &image.image));
+                  tile_ids.push_back(id);
+                  let evicted = if tile_ids.len() > TILE_TEXTURES {
+                    tile_ids.pop_front().map(|old| {
+                      image_map.remove(old);
+                      old
+                    })
+                  } else {
+                    None
+                  };
+                  tile_displayable_tx.send(DisplayableTile {
+                    file: image_result.file,
+                    region,
+                    id,
+                    width: image.image.width as u32,
+                    height: image.image.height as u32,
+                    maxwidth: image.maxwidth,
+                    ops: image.ops.clone(),
+                    evicted,
+                    output: image.clone(),
+                  }).unwrap();
+                }
               } else {
-                DisplayableState::Broken(image_result.file.clone())
-              };
-              image_displayable_tx.send(displayable).unwrap();
+                let displayable = match image_result.image {
+                  Ok(image) => {
+                    shown_image = Some((image_result.file.clone(), image.clone()));
+                    overlay_changed = true;
+                    // Create a new image
+                    let img = upload(display, &image.image);
+                    let id = if let Some(currid) = imageid {
+                      image_map.replace(currid, img);
+                      currid
+                    } else {
+                      let newid = image_map.insert(img);
+                      imageid = Some(newid);
+                      newid
+                    };
+                    DisplayableState::Present(DisplayableImage {
+                      file: image_result.file,
+                      id,
+                      width: image.image.width as u32,
+                      height: image.image.height as u32,
+                      maxwidth: image.maxwidth,
+                      maxheight: image.maxheight,
+                      ops: image.ops.clone(),
+                      default_ops: image.default_ops.clone(),
+                      output: image.clone(),
+                    })
+                  },
+                  Err(e) => DisplayableState::Broken(image_result.file.clone(), e),
+                };
+                image_displayable_tx.send(displayable).unwrap();
+              }
+            }
+            if overlay_changed {
+              update_overlay(display, &mut image_map, overlayid, &shown_image, &sensor_image, &clip_settings);
             }
 
             // Wake up conrod to redraw
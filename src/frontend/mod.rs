@@ -1,6 +1,8 @@
 pub mod logo;
 pub mod main;
 pub mod gui;
+pub mod history;
 pub mod ops;
+pub mod overlay;
 pub mod support;
 pub mod widgets;
@@ -0,0 +1,93 @@
+use crate::frontend::ops::*;
+use crate::frontend::overlay::ClipSettings;
+
+static MODES: [(&str, bool); 2] = [
+  ("Combined",    false),
+  ("Per channel", true),
+];
+
+pub fn is_unchanged(chimper: &Chimper) -> bool {
+  chimper.clipping == ClipSettings::default()
+}
+
+pub fn reset(chimper: &mut Chimper) {
+  chimper.clipping = ClipSettings::default();
+}
+
+pub fn draw_gui(chimper: &mut Chimper, ui: &mut UiCell, id: WidgetId) -> f64 {
+  let ids = &mut chimper.ids;
+  let settings = &mut chimper.clipping;
+  let mut numids = 0;
+  macro_rules! new_widget {
+    () => {{
+      numids += 1;
+      if ids.op_clipping.len() < numids {
+        ids.op_clipping.resize(numids, &mut ui.widget_id_generator());
+      }
+      ids.op_clipping[numids-1]
+    }}
+  }
+
+  let mut voffset = 36.0 * 0.5;
+  macro_rules! left_label {
+    ($name: expr) => {
+      widget::primitive::text::Text::new($name)
+        .justify(Justify::Right)
+        .w_h(140.0, 30.0)
+        .top_left_with_margins_on(id, voffset+3.0, 0.0)
+        .set(new_widget!(), ui)
+      ;
+    };
+  }
+  macro_rules! toggle_input {
+    ($name:expr, $value:expr) => {
+      left_label!($name);
+      for event in widget::toggle::Toggle::new($value)
+        .w_h(50.0, 30.0)
+        .label("ON")
+        .top_left_with_margins_on(id, voffset, 150.0)
+        .set(new_widget!(), ui)
+      {
+        $value = event;
+      }
+      voffset += 36.0;
+    };
+  }
+  macro_rules! threshold_input {
+    ($name:expr, $value:expr) => {
+      left_label!($name);
+      for event in widget::slider::Slider::new($value as f32, 0.0, 255.0)
+        .w_h(300.0, 30.0)
+        .top_left_with_margins_on(id, voffset, 150.0)
+        .set(new_widget!(), ui)
+      {
+        $value = event.round() as u8;
+      }
+      widget::primitive::text::Text::new(&$value.to_string())
+        .justify(Justify::Left)
+        .w_h(100.0, 30.0)
+        .top_left_with_margins_on(id, voffset+3.0, 460.0)
+        .set(new_widget!(), ui);
+      voffset += 36.0;
+    };
+  }
+
+  toggle_input!("Show", settings.enabled);
+  left_label!("Mark");
+  let names: Vec<&str> = MODES.iter().map(|c| c.0).collect();
+  let selected = MODES.iter().position(|c| c.1 == settings.per_channel);
+  for event in widget::drop_down_list::DropDownList::new(&names, selected)
+    .w_h(140.0, 30.0)
+    .top_left_with_margins_on(id, voffset, 150.0)
+    .set(new_widget!(), ui)
+  {
+    settings.per_channel = MODES[event].1;
+  }
+  voffset += 36.0;
+  threshold_input!("Highlights", settings.highlights);
+  threshold_input!("Shadows", settings.shadows);
+  toggle_input!("Raw clipping", settings.sensor);
+  voffset += 36.0 * 0.5;
+
+  voffset
+}
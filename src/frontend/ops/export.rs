@@ -0,0 +1,291 @@
+use crate::frontend::ops::*;
+
+static FORMATS: [(&str, ExportFormat); 4] = [
+  ("JPEG", ExportFormat::Jpeg),
+  ("PNG",  ExportFormat::Png),
+  ("TIFF", ExportFormat::Tiff),
+  ("WebP", ExportFormat::WebP),
+];
+
+static BITDEPTHS: [(&str, BitDepth); 2] = [
+  ("8 bit",  BitDepth::Eight),
+  ("16 bit", BitDepth::Sixteen),
+];
+
+static SUBSAMPLINGS: [(&str, ChromaSubsampling); 3] = [
+  ("4:4:4", ChromaSubsampling::Yuv444),
+  ("4:2:2", ChromaSubsampling::Yuv422),
+  ("4:2:0", ChromaSubsampling::Yuv420),
+];
+
+static COLORSPACES: [(&str, ColorSpace); 5] = [
+  ("sRGB",            ColorSpace::Srgb),
+  ("Display P3",      ColorSpace::DisplayP3),
+  ("Adobe RGB",       ColorSpace::AdobeRgb),
+  ("ProPhoto",        ColorSpace::ProPhoto),
+  ("Linear Rec.2020", ColorSpace::LinearRec2020),
+];
+
+static METADATA: [(&str, MetadataPolicy); 4] = [
+  ("Keep all",        MetadataPolicy::Keep),
+  ("Remove GPS",      MetadataPolicy::StripGps),
+  ("Remove personal", MetadataPolicy::StripPersonal),
+  ("Remove all",      MetadataPolicy::StripAll),
+];
+
+static EDITS: [(&str, bool); 2] = [
+  ("Each file's own", false),
+  ("This image's",    true),
+];
+
+static COLLISIONS: [(&str, CollisionPolicy); 3] = [
+  ("Add suffix", CollisionPolicy::Suffix),
+  ("Skip",       CollisionPolicy::Skip),
+  ("Overwrite",  CollisionPolicy::Overwrite),
+];
+
+// Only show the latest exports so the list doesn't grow forever
+static MAX_JOBS_SHOWN: usize = 10;
+
+pub fn is_unchanged(chimper: &Chimper) -> bool {
+  chimper.export_settings == ExportSettings::default() &&
+    chimper.export_destination == ExportDestination::default()
+}
+
+pub fn reset(chimper: &mut Chimper) {
+  chimper.export_settings = ExportSettings::default();
+  chimper.export_destination = ExportDestination::default();
+}
+
+pub fn draw_gui(chimper: &mut Chimper, ui: &mut UiCell, id: WidgetId) -> f64 {
+  let ids = &mut chimper.ids;
+  let settings = &mut chimper.export_settings;
+  let destination = &mut chimper.export_destination;
+  let mut numids = 0;
+  macro_rules! new_widget {
+    () => {{
+      numids += 1;
+      if ids.op_export.len() < numids {
+        ids.op_export.resize(numids, &mut ui.widget_id_generator());
+      }
+      ids.op_export[numids-1]
+    }}
+  }
+
+  let mut voffset = 36.0 * 0.5;
+  macro_rules! left_label {
+    ($name: expr) => {
+      widget::primitive::text::Text::new($name)
+        .justify(Justify::Right)
+        .w_h(140.0, 30.0)
+        .top_left_with_margins_on(id, voffset+3.0, 0.0)
+        .set(new_widget!(), ui)
+      ;
+    };
+  }
+  macro_rules! label_hint {
+    ($name: expr) => {
+      widget::primitive::text::Text::new($name)
+        .justify(Justify::Left)
+        .font_size(12)
+        .w_h(400.0, 20.0)
+        .top_left_with_margins_on(id, voffset, 150.0)
+        .set(new_widget!(), ui)
+      ;
+      voffset += 24.0;
+    };
+  }
+  macro_rules! choice_input {
+    ($name:expr, $choices:expr, $value:expr) => {
+      left_label!($name);
+      let names: Vec<&str> = $choices.iter().map(|c| c.0).collect();
+      let selected = $choices.iter().position(|c| c.1 == $value);
+      for event in widget::drop_down_list::DropDownList::new(&names, selected)
+        .w_h(140.0, 30.0)
+        .top_left_with_margins_on(id, voffset, 150.0)
+        .set(new_widget!(), ui)
+      {
+        $value = $choices[event].1;
+      }
+      voffset += 36.0;
+    };
+  }
+
+  choice_input!("Format", FORMATS, settings.format);
+  if settings.format.supports_16bit() {
+    choice_input!("Bit depth", BITDEPTHS, settings.bitdepth);
+  }
+  if settings.format == ExportFormat::Jpeg {
+    left_label!("Quality");
+    for event in widget::slider::Slider::new(settings.quality as f32, 1.0, 100.0)
+      .w_h(300.0, 30.0)
+      .top_left_with_margins_on(id, voffset, 150.0)
+      .set(new_widget!(), ui)
+    {
+      settings.quality = event.round() as u8;
+    }
+    widget::primitive::text::Text::new(&settings.quality.to_string())
+      .justify(Justify::Left)
+      .w_h(100.0, 30.0)
+      .top_left_with_margins_on(id, voffset+3.0, 460.0)
+      .set(new_widget!(), ui);
+    voffset += 36.0;
+
+    choice_input!("Subsampling", SUBSAMPLINGS, settings.subsampling);
+  }
+  let is_16bit = settings.is_16bit();
+  let colorspaces: Vec<(&str, ColorSpace)> = COLORSPACES.iter()
+    .filter(|c| is_16bit || !c.1.needs_16bit())
+    .cloned()
+    .collect();
+  choice_input!("Color space", colorspaces, settings.colorspace);
+  choice_input!("Metadata", METADATA, settings.metadata);
+
+  voffset += 36.0 * 0.5;
+  left_label!("Directory");
+  let dirtext = destination.directory.clone().unwrap_or_default();
+  for event in widget::text_box::TextBox::new(&dirtext)
+    .left_justify()
+    .w_h(400.0, 30.0)
+    .top_left_with_margins_on(id, voffset, 150.0)
+    .set(new_widget!(), ui)
+  {
+    if let widget::text_box::Event::Update(val) = event {
+      destination.directory = if val.trim() == "" { None } else { Some(val) };
+    }
+  }
+  voffset += 36.0;
+  label_hint!("Leave empty to export next to the image");
+
+  left_label!("File name");
+  for event in widget::text_box::TextBox::new(&destination.template.clone())
+    .left_justify()
+    .w_h(400.0, 30.0)
+    .top_left_with_margins_on(id, voffset, 150.0)
+    .set(new_widget!(), ui)
+  {
+    if let widget::text_box::Event::Update(val) = event {
+      destination.template = val;
+    }
+  }
+  voffset += 36.0;
+  label_hint!("Tokens: {stem} {ext} {date} {camera} {seq}");
+
+  choice_input!("If it exists", COLLISIONS, destination.collision);
+
+  voffset += 36.0 * 0.5;
+  left_label!("Batch");
+  let mut export_dir = false;
+  for _ in widget::Button::new()
+    .label("Whole directory")
+    .w_h(195.0, 30.0)
+    .top_left_with_margins_on(id, voffset, 150.0)
+    .set(new_widget!(), ui)
+  {
+    export_dir = true;
+  }
+  let mut export_selection = false;
+  let nselected = chimper.selection.len();
+  for _ in widget::Button::new()
+    .label(&format!("Selection ({})", nselected))
+    .w_h(195.0, 30.0)
+    .top_left_with_margins_on(id, voffset, 355.0)
+    .set(new_widget!(), ui)
+  {
+    export_selection = nselected > 0;
+  }
+  voffset += 36.0;
+  choice_input!("Using edits", EDITS, chimper.export_shared_ops);
+
+  // Show how the latest exports are going, newest first
+  let mut cancel = Vec::new();
+  let mut clear = false;
+  if !chimper.exports.is_empty() {
+    voffset += 36.0 * 0.5;
+    let total = chimper.exports.len();
+    let finished = chimper.exports.iter().filter(|job| job.state.is_finished()).count();
+    let failed = chimper.exports.iter().filter(|job| matches!(job.state, ExportState::Failed(_))).count();
+    left_label!("Exports");
+    widget::primitive::shape::rectangle::Rectangle::fill([300.0, 30.0])
+      .color(color::DARK_GREY)
+      .top_left_with_margins_on(id, voffset, 150.0)
+      .set(new_widget!(), ui);
+    widget::primitive::shape::rectangle::Rectangle::fill([300.0 * finished as f64 / total as f64, 30.0])
+      .color(if failed > 0 { color::LIGHT_RED } else { color::LIGHT_GREEN })
+      .top_left_with_margins_on(id, voffset, 150.0)
+      .set(new_widget!(), ui);
+    let summary = if failed > 0 {
+      format!("{}/{} ({} failed)", finished, total, failed)
+    } else {
+      format!("{}/{}", finished, total)
+    };
+    widget::primitive::text::Text::new(&summary)
+      .justify(Justify::Center)
+      .w_h(300.0, 30.0)
+      .top_left_with_margins_on(id, voffset+3.0, 150.0)
+      .set(new_widget!(), ui);
+    voffset += 36.0;
+    for _ in widget::Button::new()
+      .label("Clear finished")
+      .w_h(140.0, 30.0)
+      .top_left_with_margins_on(id, voffset, 150.0)
+      .set(new_widget!(), ui)
+    {
+      clear = true;
+    }
+    voffset += 36.0;
+  }
+  for job in chimper.exports.iter().rev().take(MAX_JOBS_SHOWN) {
+    // Still queued until a worker confirms it dropped the job, or too late if
+    // one had already started it
+    let cancelling = chimper.export_cancelled.lock().unwrap().contains(&job.id);
+    let name = std::path::Path::new(&job.file).file_name()
+      .map(|n| n.to_string_lossy().to_string())
+      .unwrap_or_else(|| job.file.clone());
+    let state = match job.state {
+      ExportState::Queued if cancelling => "cancelling...".to_string(),
+      ExportState::Queued => "queued".to_string(),
+      ExportState::Running if cancelling => "already exporting, can't cancel".to_string(),
+      ExportState::Running => "exporting...".to_string(),
+      ExportState::Done(ref path) => format!("saved {}", path.display()),
+      ExportState::Failed(ref e) => format!("failed: {}", e),
+      ExportState::Skipped(ref path) => format!("skipped, {} exists", path.display()),
+      ExportState::Cancelled => "cancelled".to_string(),
+    };
+    widget::primitive::text::Text::new(&format!("{} - {}", name, state))
+      .justify(Justify::Left)
+      .font_size(12)
+      .w_h(360.0, 30.0)
+      .top_left_with_margins_on(id, voffset+6.0, 20.0)
+      .set(new_widget!(), ui);
+    if job.state == ExportState::Queued && !cancelling {
+      for _ in widget::Button::new()
+        .label("Cancel")
+        .w_h(80.0, 26.0)
+        .top_left_with_margins_on(id, voffset, 390.0)
+        .set(new_widget!(), ui)
+      {
+        cancel.push(job.id);
+      }
+    }
+    voffset += 30.0;
+  }
+
+  for id in cancel {
+    chimper.cancel_export(id);
+  }
+  if export_dir {
+    chimper.export_directory();
+  }
+  if export_selection {
+    let files = chimper.selection.clone();
+    chimper.export_batch(files);
+  }
+  if clear {
+    chimper.exports.retain(|job| !job.state.is_finished());
+  }
+
+  voffset += 36.0 * 0.5;
+
+  voffset
+}
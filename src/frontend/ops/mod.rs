@@ -16,6 +16,9 @@ pub mod tolab;
 mod basecurve;
 mod transform;
 mod rotatecrop;
+mod undo;
+mod clipping;
+mod export;
 
 pub fn draw_gui(chimper: &mut Chimper, ui: &mut UiCell) {
   if chimper.ops.is_some() {
@@ -105,6 +108,9 @@ pub fn draw_gui(chimper: &mut Chimper, ui: &mut UiCell) {
     draw_op!("basecurve",  basecurve, SelectedOp::Basecurve);
     draw_op!("transform",  transform, SelectedOp::Transform);
     draw_op!("rotate and crop",  rotatecrop, SelectedOp::RotateCrop);
+    draw_op!("clipping",   clipping,  SelectedOp::Clipping);
+    draw_op!("history",    undo,      SelectedOp::History);
+    draw_op!("export",     export,    SelectedOp::Export);
 
     for _ in widget::Button::new()
       .label("Export")
@@ -120,7 +126,7 @@ pub fn draw_gui(chimper: &mut Chimper, ui: &mut UiCell) {
         } else {
           None
         };
-        chimper.export_request_tx.send(RequestedExport{file, ops}).unwrap();
+        chimper.export(file, ops);
       } else {
         log::error!("Trying to export with no file selected!");
       }
@@ -1,4 +1,5 @@
 use crate::frontend::ops::*;
+use crate::backend::colorspace;
 use imagepipe::color_conversions::*;
 
 static MIN_TEMP: f32 = 2000.0;
@@ -27,6 +28,104 @@ pub fn temp_tint_image() -> ((u32, u32), Vec<u8>) {
   ((width as u32, height as u32), data)
 }
 
+// Smallest box that gets sampled, in pixels of the render, so that a single
+// click still averages out some noise
+static MIN_PICK: f64 = 5.0;
+
+/// Set the white balance so that a box of the image, as fractions of it, comes
+/// out neutral. The render is taken back to camera space using the white
+/// balance it was made with and then the temperature and tint that best
+/// neutralize that are searched for.
+pub fn pick_neutral(chimper: &mut Chimper, rect: (f64, f64, f64, f64)) {
+  let output = match chimper.image {
+    DisplayableState::Present(ref image) => image.output.clone(),
+    DisplayableState::Requested(_, Some(ref image)) => image.output.clone(),
+    _ => return,
+  };
+  let ops = if let Some((ref mut ops, _)) = chimper.ops { ops } else { return };
+
+  let image = &output.image;
+  let (width, height) = (image.width as f64, image.height as f64);
+  let (cx, cy) = ((rect.0 + rect.2) / 2.0 * width, (rect.1 + rect.3) / 2.0 * height);
+  let halfw = ((rect.2 - rect.0) * width).max(MIN_PICK) / 2.0;
+  let halfh = ((rect.3 - rect.1) * height).max(MIN_PICK) / 2.0;
+  let (x0, x1) = ((cx - halfw).max(0.0) as usize, ((cx + halfw) as usize).min(image.width));
+  let (y0, y1) = ((cy - halfh).max(0.0) as usize, ((cy + halfh) as usize).min(image.height));
+
+  // Average in linear light, leaving out anything clipped as it's not telling
+  // us the real color
+  let mut sum = [0.0; 3];
+  let mut count = 0;
+  for row in y0..y1 {
+    for col in x0..x1 {
+      let pos = (row * image.width + col) * 3;
+      let pixel = &image.data[pos..pos+3];
+      if pixel.iter().any(|v| *v == 0 || *v == 255) {
+        continue
+      }
+      for (s, v) in sum.iter_mut().zip(pixel.iter()) {
+        *s += colorspace::srgb_decode(*v);
+      }
+      count += 1;
+    }
+  }
+  if count == 0 {
+    log::info!("Nothing usable to pick the white balance from");
+    return
+  }
+  let avg = sum.map(|s| s / count as f64);
+
+  // What the camera saw under the white balance of the render
+  let tolab = &output.ops.tolab;
+  let cam_to_xyz = [0, 1, 2].map(|row| [0, 1, 2].map(|col| tolab.cam_to_xyz[row][col] as f64));
+  let balanced = colorspace::solve(&cam_to_xyz, colorspace::srgb_linear_to_xyz(avg));
+  let camera = [0, 1, 2].map(|c| balanced[c] / tolab.wb_coeffs[c] as f64);
+  if camera.iter().any(|v| *v <= 0.0) {
+    log::info!("Can't make a neutral out of {:?}", avg);
+    return
+  }
+  // The multipliers that make it neutral, relative to green
+  let wanted = [camera[1] / camera[0], 1.0, camera[1] / camera[2]];
+
+  let (temp, tint) = solve_temp_tint(ops, wanted);
+  log::debug!("Picked temp/tint {}/{} for multipliers {:?}", temp, tint, wanted);
+  ops.tolab.set_temp(temp, tint);
+}
+
+// Search for the temperature and tint whose multipliers are closest to the
+// wanted ones, first on a coarse grid and then closing in on the best
+fn solve_temp_tint(ops: &PipelineOps, wanted: [f64; 3]) -> (f32, f32) {
+  let error = |temp: f32, tint: f32| {
+    let mut ops = ops.clone();
+    ops.tolab.set_temp(temp, tint);
+    let coeffs = ops.tolab.wb_coeffs;
+    let (red, green, blue) = (coeffs[0] as f64, coeffs[1] as f64, coeffs[2] as f64);
+    ((red / green).ln() - wanted[0].ln()).powi(2) + ((blue / green).ln() - wanted[2].ln()).powi(2)
+  };
+
+  let steps = 20;
+  let (mut tempstep, mut tintstep) = ((MAX_TEMP - MIN_TEMP) / steps as f32, (MAX_TINT - MIN_TINT) / steps as f32);
+  let (mut best, mut besterr) = ((MIN_TEMP, MIN_TINT), f64::INFINITY);
+  let mut center = ((MIN_TEMP + MAX_TEMP) / 2.0, (MIN_TINT + MAX_TINT) / 2.0);
+  for _ in 0..5 {
+    for i in -steps/2..=steps/2 {
+      for j in -steps/2..=steps/2 {
+        let temp = (center.0 + i as f32 * tempstep).max(MIN_TEMP).min(MAX_TEMP);
+        let tint = (center.1 + j as f32 * tintstep).max(MIN_TINT).min(MAX_TINT);
+        let err = error(temp, tint);
+        if err < besterr {
+          best = (temp, tint);
+          besterr = err;
+        }
+      }
+    }
+    center = best;
+    tempstep /= 5.0;
+    tintstep /= 5.0;
+  }
+  best
+}
+
 pub fn is_unchanged(chimper: &Chimper) -> bool {
   if let Some(ref ops) = chimper.ops {
     let (ops, default_ops) =  ops;
@@ -70,6 +169,15 @@ pub fn draw_gui(chimper: &mut Chimper, ui: &mut UiCell, id: WidgetId) -> f64 {
     };
   }
 
+  for event in widget::toggle::Toggle::new(chimper.wb_picker)
+    .w_h(110.0, 30.0)
+    .label("Pick neutral")
+    .top_left_with_margins_on(id, voffset, 470.0)
+    .set(new_widget!(), ui)
+  {
+    chimper.wb_picker = event;
+  }
+
   let mut altered = false;
   let (otemp, otint) = ops.tolab.get_temp();
   let mut temp = otemp;
@@ -157,3 +265,23 @@ pub fn draw_gui(chimper: &mut Chimper, ui: &mut UiCell, id: WidgetId) -> f64 {
 
   voffset
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::backend::testutil;
+
+  #[test]
+  fn finds_temp_tint() {
+    let ops = testutil::default_ops();
+    for &(temp, tint) in [(3200.0, 1.0), (5500.0, 1.1), (9000.0, 0.9)].iter() {
+      let mut target = ops.clone();
+      target.tolab.set_temp(temp, tint);
+      let coeffs = target.tolab.wb_coeffs;
+      let wanted = [(coeffs[0] / coeffs[1]) as f64, 1.0, (coeffs[2] / coeffs[1]) as f64];
+      let (found_temp, found_tint) = solve_temp_tint(&ops, wanted);
+      assert!((found_temp - temp).abs() < temp * 0.01, "{} instead of {}K", found_temp, temp);
+      assert!((found_tint - tint).abs() < 0.01, "{} instead of a tint of {}", found_tint, tint);
+    }
+  }
+}
@@ -0,0 +1,55 @@
+use crate::frontend::ops::*;
+
+pub fn is_unchanged(chimper: &Chimper) -> bool {
+  !chimper.history.can_undo()
+}
+
+pub fn reset(chimper: &mut Chimper) {
+  // Go all the way back to where the history started
+  chimper.history_jump(0);
+}
+
+pub fn draw_gui(chimper: &mut Chimper, ui: &mut UiCell, id: WidgetId) -> f64 {
+  let ids = &mut chimper.ids;
+  let (entries, currpos) = chimper.history.entries();
+  let mut numids = 0;
+  macro_rules! new_widget {
+    () => {{
+      numids += 1;
+      if ids.op_history.len() < numids {
+        ids.op_history.resize(numids, &mut ui.widget_id_generator());
+      }
+      ids.op_history[numids-1]
+    }}
+  }
+
+  let mut voffset = 36.0 * 0.25;
+  let mut jumpto = None;
+  for (pos, entry) in entries.iter().enumerate().rev() {
+    let color = if pos == currpos {
+      color::LIGHT_BLUE
+    } else if pos > currpos {
+      color::DARK_GREY
+    } else {
+      color::LIGHT_GREY
+    };
+    for _ in widget::Button::new()
+      .label(&format!("{}. {}", pos+1, entry.name))
+      .color(color)
+      .w_h(400.0, 26.0)
+      .top_left_with_margins_on(id, voffset, 100.0)
+      .set(new_widget!(), ui)
+    {
+      jumpto = Some(pos);
+    }
+    voffset += 30.0;
+  }
+
+  if let Some(pos) = jumpto {
+    chimper.history_jump(pos);
+  }
+
+  voffset += 36.0 * 0.25;
+
+  voffset
+}
@@ -0,0 +1,77 @@
+extern crate imagepipe;
+use self::imagepipe::SRGBImage;
+
+/// What the clipping overlay marks and where the limits are
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ClipSettings {
+  pub enabled: bool,
+  /// Mark each channel on its own instead of only where all of them clip
+  pub per_channel: bool,
+  /// Values at or above this count as blown highlights
+  pub highlights: u8,
+  /// Values at or below this count as crushed shadows
+  pub shadows: u8,
+  /// Also mark what was already clipped at the raw white level
+  pub sensor: bool,
+}
+
+impl Default for ClipSettings {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      per_channel: false,
+      highlights: 254,
+      shadows: 1,
+      sensor: false,
+    }
+  }
+}
+
+// How strongly marked pixels get covered
+const ALPHA: u8 = 200;
+// A sensor mask pixel this bright had at least one clipped photosite under it
+const SENSOR_THRESHOLD: u8 = 64;
+
+/// An RGBA image to draw over the render marking the clipped pixels. The
+/// sensor mask is the render of the same image with the ops set up to only
+/// let through what was clipped in the raw, see `RequestedImage`.
+pub fn clipping(image: &SRGBImage, sensor: Option<&SRGBImage>, settings: &ClipSettings) -> Vec<u8> {
+  // A mask for some other render would mark the wrong pixels
+  let sensor = sensor.filter(|s| s.width == image.width && s.height == image.height);
+  let mut out = vec![0; image.width * image.height * 4];
+  for (i, (pixel, outpixel)) in image.data.chunks_exact(3).zip(out.chunks_exact_mut(4)).enumerate() {
+    if let Some(sensor) = sensor {
+      let mask = &sensor.data[i*3..i*3+3];
+      if mask.iter().any(|v| *v >= SENSOR_THRESHOLD) {
+        outpixel.copy_from_slice(&[255, 0, 255, ALPHA]);
+        continue
+      }
+    }
+
+    let high = [0, 1, 2].map(|c| pixel[c] >= settings.highlights);
+    let low = [0, 1, 2].map(|c| pixel[c] <= settings.shadows);
+    let color = if settings.per_channel {
+      if high.iter().any(|h| *h) {
+        // Show which channels blew out, all of them comes out white
+        Some(high.map(|h| if h { 255 } else { 0 }))
+      } else if low.iter().all(|l| *l) {
+        Some([0, 0, 255])
+      } else if low.iter().any(|l| *l) {
+        // The complement of the crushed channels, so crushed blue is yellow
+        Some(low.map(|l| if l { 0 } else { 255 }))
+      } else {
+        None
+      }
+    } else if high.iter().all(|h| *h) {
+      Some([255, 0, 0])
+    } else if low.iter().all(|l| *l) {
+      Some([0, 0, 255])
+    } else {
+      None
+    };
+    if let Some([r, g, b]) = color {
+      outpixel.copy_from_slice(&[r, g, b, ALPHA]);
+    }
+  }
+  out
+}
@@ -0,0 +1,128 @@
+//! Used for showing the histogram of the image being edited
+
+use conrod_core::widget;
+use conrod_core::{color, Color, Colorable, Positionable, Sizeable, Widget};
+use crate::backend::histogram::Histogram;
+
+#[derive(WidgetCommon)]
+pub struct HistogramView<'a> {
+  #[conrod(common_builder)]
+  common: widget::CommonBuilder,
+  style: Style,
+  histogram: &'a Histogram,
+  /// Whether to scale the counts logarithmically so the shadows and
+  /// highlights don't disappear next to the peaks
+  pub log: bool,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, WidgetStyle)]
+pub struct Style {
+  /// The color of the background
+  #[conrod(default = "color::CHARCOAL")]
+  pub color: Option<Color>,
+}
+
+struct Ids {
+  rectangle: conrod_core::widget::Id,
+  lines: conrod_core::widget::id::List,
+}
+
+impl Ids {
+  pub fn new(mut generator: conrod_core::widget::id::Generator) -> Self {
+    let mut lines = conrod_core::widget::id::List::new();
+    lines.resize(4, &mut generator);
+    Ids {
+      rectangle: generator.next(),
+      lines,
+    }
+  }
+}
+
+pub struct State {
+  ids: Ids,
+}
+
+impl<'a> HistogramView<'a> {
+  pub fn new(histogram: &'a Histogram) -> Self {
+    Self {
+      common: widget::CommonBuilder::default(),
+      style: Style::default(),
+      histogram,
+      log: false,
+    }
+  }
+
+  builder_methods! {
+    pub log { log = bool }
+  }
+}
+
+impl<'a> Widget for HistogramView<'a> {
+  type State = State;
+  type Style = Style;
+  type Event = ();
+
+  fn init_state(&self, id_gen: widget::id::Generator) -> Self::State {
+    State {
+      ids: Ids::new(id_gen),
+    }
+  }
+
+  fn style(&self) -> Self::Style {
+    self.style.clone()
+  }
+
+  fn update(self, args: widget::UpdateArgs<Self>) -> Self::Event {
+    let widget::UpdateArgs {
+      id,
+      state,
+      rect,
+      style,
+      ui,
+      ..
+    } = args;
+    let Self {
+      histogram,
+      log,
+      ..
+    } = self;
+
+    widget::Rectangle::fill(rect.dim())
+      .middle_of(id)
+      .graphics_for(id)
+      .color(style.color(ui.theme()))
+      .set(state.ids.rectangle, ui);
+
+    let peak = histogram.peak().max(1) as f64;
+    let scale = |count: u32| -> f64 {
+      let height = if log {
+        (1.0 + count as f64).ln() / (1.0 + peak).ln()
+      } else {
+        count as f64 / peak
+      };
+      height.min(1.0)
+    };
+
+    let channels = [
+      (&histogram.red, color::RED),
+      (&histogram.green, color::GREEN),
+      (&histogram.blue, color::BLUE),
+      (&histogram.luma, color::WHITE),
+    ];
+    for (i, (values, color)) in channels.iter().enumerate() {
+      widget::plot_path::PlotPath::new(0.0, 255.0, 0.0, 1.0, |x: f64| {
+        scale(values[(x.round() as usize).min(255)])
+      })
+        .wh_of(id)
+        .middle_of(id)
+        .graphics_for(id)
+        .color(color.with_alpha(0.8))
+        .thickness(1.0)
+        .set(state.ids.lines[i], ui);
+    }
+  }
+}
+
+impl<'a> Colorable for HistogramView<'a> {
+  builder_method!(color { style.color = Some(Color) });
+}
@@ -2,8 +2,63 @@
 
 use conrod_core::utils::map_range;
 use conrod_core::widget;
-use conrod_core::{Colorable, Sizeable, Positionable, Widget};
+use conrod_core::{Colorable, Sizeable, Positionable, Widget, Rect};
 use conrod_core::color::RED;
+use conrod_core::input::Key;
+
+/// How big to show the image
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Zoom {
+  /// The whole image inside the view, never scaled up
+  Fit,
+  /// Cover the whole view, cutting off what doesn't fit
+  Fill,
+  /// Screen pixels per pixel of the full size image
+  Scale(f64),
+}
+
+/// How to show the render with the default ops next to the edited one
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Compare {
+  Off,
+  /// Unedited left of a divider at a fraction of the view width, edited right
+  Split(f64),
+  /// Each in its own half of the view, unedited on the left
+  SideBySide,
+}
+
+static MIN_ZOOM: f64 = 0.05;
+static MAX_ZOOM: f64 = 8.0;
+// How much a single step of the wheel or the keyboard zooms in or out
+static ZOOM_STEP: f64 = 1.25;
+// Scroll distance conrod reports for a single line of the mouse wheel
+static SCROLL_LINE: f64 = 10.0;
+// How close to the divider a drag has to start to move it instead of panning
+static DIVIDER_GRAB: f64 = 8.0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ViewEvent {
+  /// The crops were changed by dragging the handles
+  Crop((f64,f64,f64,f64)),
+  /// The zoom or the center of the view, as a fraction of the image, changed
+  View(Zoom, (f64,f64)),
+  /// The cursor moved to a point of the image, as fractions of it, or left it
+  Hover(Option<(f64,f64)>),
+  /// A box was picked out of the image, as fractions of it from the top left
+  /// to the bottom right corner
+  Pick((f64,f64,f64,f64)),
+  /// The comparison mode was switched or its divider dragged
+  Compare(Compare),
+}
+
+/// A sharper render of part of the image to draw over the whole one
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ViewTile {
+  pub id: conrod_core::image::Id,
+  pub dims: (f64, f64),
+  /// Left, top, right and bottom edges as fractions of the image
+  pub rect: [f64; 4],
+}
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 enum ResizeMode {
@@ -42,6 +97,20 @@ pub struct ImageView {
   style: Style,
   image_id: conrod_core::image::Id,
   crops: Option<(f64,f64,f64,f64)>,
+  // Size of the render in the texture and of the full size image if known
+  image_dims: (f64, f64),
+  native_dims: Option<(f64, f64)>,
+  zoom: Zoom,
+  pan: (f64, f64),
+  tiles: Vec<ViewTile>,
+  // Drawn over the image and exactly the same size as its texture
+  overlay: Option<conrod_core::image::Id>,
+  // Dragging picks out a box of the image instead of panning
+  picking: bool,
+  compare: Compare,
+  // The unedited render and its size, drawn at the same fractions of the
+  // image as the edited one
+  before: Option<(conrod_core::image::Id, (f64, f64))>,
 }
 
 #[derive(Copy, Clone, Debug, Default, PartialEq, WidgetStyle)]
@@ -50,7 +119,12 @@ pub struct Style {
 
 struct Ids {
   image: conrod_core::widget::Id,
+  overlay: conrod_core::widget::Id,
+  pick: conrod_core::widget::Id,
+  before: conrod_core::widget::Id,
+  divider: conrod_core::widget::Id,
   handles: conrod_core::widget::id::List,
+  tiles: conrod_core::widget::id::List,
 }
 
 impl Ids {
@@ -59,7 +133,12 @@ impl Ids {
     handles.resize(4, &mut generator);
     Ids {
       image: generator.next(),
+      overlay: generator.next(),
+      pick: generator.next(),
+      before: generator.next(),
+      divider: generator.next(),
       handles,
+      tiles: conrod_core::widget::id::List::new(),
     }
   }
 }
@@ -74,6 +153,11 @@ struct Drag {
 pub struct State {
   ids: Ids,
   drag: Option<Drag>,
+  hover: Option<(f64, f64)>,
+  // Where the box being picked was started
+  pick: Option<(f64, f64)>,
+  // A drag that started on the divider is moving it
+  dividing: bool,
 }
 
 impl<'a> ImageView {
@@ -84,14 +168,54 @@ impl<'a> ImageView {
       style: Style::default(),
       image_id,
       crops,
+      image_dims: (1.0, 1.0),
+      native_dims: None,
+      zoom: Zoom::Fit,
+      pan: (0.5, 0.5),
+      tiles: Vec::new(),
+      overlay: None,
+      picking: false,
+      compare: Compare::Off,
+      before: None,
     }
   }
+
+  builder_methods! {
+    pub image_dims { image_dims = (f64, f64) }
+    pub native_dims { native_dims = Option<(f64, f64)> }
+    pub zoom { zoom = Zoom }
+    pub pan { pan = (f64, f64) }
+    pub tiles { tiles = Vec<ViewTile> }
+    pub overlay { overlay = Option<conrod_core::image::Id> }
+    pub picking { picking = bool }
+    pub compare { compare = Compare }
+    pub before { before = Option<(conrod_core::image::Id, (f64, f64))> }
+  }
+}
+
+// Screen pixels per texture pixel for a given zoom
+fn texture_scale(zoom: Zoom, view: [f64; 2], image: (f64, f64), native: Option<(f64, f64)>) -> f64 {
+  match zoom {
+    Zoom::Fit => (view[0] / image.0).min(view[1] / image.1).min(1.0),
+    Zoom::Fill => (view[0] / image.0).max(view[1] / image.1),
+    // Until a full size render shows up pretend this one is full size
+    Zoom::Scale(scale) => scale * native.map(|n| n.0 / image.0).unwrap_or(1.0),
+  }
+}
+
+// Keep the center of the view somewhere that doesn't show past the image edge
+fn clamp_pan(pan: f64, visible: f64) -> f64 {
+  if visible >= 1.0 {
+    0.5
+  } else {
+    pan.max(visible / 2.0).min(1.0 - visible / 2.0)
+  }
 }
 
 impl Widget for ImageView {
   type State = State;
   type Style = Style;
-  type Event = Option<(f64,f64,f64,f64)>;
+  type Event = Vec<ViewEvent>;
 
   fn init_state(&self, id_gen: widget::id::Generator) -> Self::State {
     // Save an extra for hover
@@ -99,6 +223,9 @@ impl Widget for ImageView {
     State {
       ids,
       drag: None,
+      hover: None,
+      pick: None,
+      dividing: false,
     }
   }
 
@@ -118,10 +245,19 @@ impl Widget for ImageView {
     let Self {
       image_id,
       crops,
+      image_dims,
+      native_dims,
+      zoom,
+      pan,
+      tiles,
+      overlay,
+      picking,
+      compare,
+      before,
       ..
     } = self;
 
-    let mut event = None;
+    let mut events = Vec::new();
     if let Some(crops) = crops {
       let mut crop_top = crops.0;
       let mut crop_right = crops.1;
@@ -171,7 +307,7 @@ impl Widget for ImageView {
           if !mouse.buttons.left().is_down() {
             // We're no longer clicking so reset the state
             state.update(|state| state.drag = None);
-            event = Some((crop_top, crop_right, crop_bottom, crop_left));
+            events.push(ViewEvent::Crop((crop_top, crop_right, crop_bottom, crop_left)));
           }
         } else {
           if new_x < 0.0+crop_left && new_y < 0.0+crop_top {
@@ -232,14 +368,250 @@ impl Widget for ImageView {
         .color(if highlight.bottom() {color_highlight} else {color})
         .set(state.ids.handles[3], ui);
     } else {
-      // We weren't initialized in crop mode so just show the image
-      let [width, height] = rect.dim();
+      // We weren't initialized in crop mode so show the image zoomed and panned,
+      // side by side in the right half with the unedited one in the left
+      let area = if compare == Compare::SideBySide {
+        Rect::from_corners([rect.x(), rect.bottom()], [rect.right(), rect.top()])
+      } else {
+        rect
+      };
+      let view = area.dim();
+      let (iwidth, iheight) = image_dims;
+      let native_scale = native_dims.map(|n| n.0 / iwidth).unwrap_or(1.0);
+      let mut scale = texture_scale(zoom, view, image_dims, native_dims);
+      let mut newzoom = zoom;
+      let (mut panx, mut pany) = pan;
+
+      let input = ui.widget_input(id);
+      // Change the scale while keeping whatever is under a point where it is
+      let rezoom = |newscale: f64, around: [f64; 2], scale: &mut f64, panx: &mut f64, pany: &mut f64| {
+        let newscale = newscale.max(MIN_ZOOM * native_scale).min(MAX_ZOOM * native_scale);
+        let (dx, dy) = (around[0] - area.x(), around[1] - area.y());
+        let imgx = *panx + dx / (*scale * iwidth);
+        let imgy = *pany - dy / (*scale * iheight);
+        *panx = imgx - dx / (newscale * iwidth);
+        *pany = imgy + dy / (newscale * iheight);
+        *scale = newscale;
+        Zoom::Scale(newscale / native_scale)
+      };
+      for scroll in input.scrolls() {
+        // Scrolling up comes in as negative
+        let steps = -scroll.y / SCROLL_LINE;
+        let around = input.mouse().map(|m| m.abs_xy()).unwrap_or([area.x(), area.y()]);
+        newzoom = rezoom(scale * ZOOM_STEP.powf(steps), around, &mut scale, &mut panx, &mut pany);
+      }
+      let mut newcompare = compare;
+      for press in input.presses().key() {
+        let center = [area.x(), area.y()];
+        newzoom = match press.key {
+          Key::D0 => Zoom::Fit,
+          Key::F => Zoom::Fill,
+          Key::D1 => Zoom::Scale(1.0),
+          Key::D2 => Zoom::Scale(2.0),
+          Key::B => {
+            newcompare = match compare {
+              Compare::Off => Compare::Split(0.5),
+              Compare::Split(_) => Compare::SideBySide,
+              Compare::SideBySide => Compare::Off,
+            };
+            newzoom
+          },
+          Key::Equals | Key::Plus | Key::NumPadPlus => {
+            rezoom(scale * ZOOM_STEP, center, &mut scale, &mut panx, &mut pany)
+          },
+          Key::Minus | Key::NumPadMinus => {
+            rezoom(scale / ZOOM_STEP, center, &mut scale, &mut panx, &mut pany)
+          },
+          _ => newzoom,
+        };
+        scale = texture_scale(newzoom, view, image_dims, native_dims);
+      }
+      let mut dividing = state.dividing;
+      for drag in input.drags().left() {
+        if let Compare::Split(split) = newcompare {
+          let divider = rect.left() + split * rect.w();
+          if !dividing && (rect.x() + drag.origin[0] - divider).abs() < DIVIDER_GRAB {
+            dividing = true;
+          }
+          if dividing {
+            let x = rect.x() + drag.to[0];
+            newcompare = Compare::Split(((x - rect.left()) / rect.w()).max(0.0).min(1.0));
+            continue
+          }
+        }
+        if !picking {
+          panx -= drag.delta_xy[0] / (scale * iwidth);
+          pany += drag.delta_xy[1] / (scale * iheight);
+        }
+      }
+      if !ui.global_input().current.mouse.buttons.left().is_down() {
+        dividing = false;
+      }
+      if dividing != state.dividing {
+        state.update(|state| state.dividing = dividing);
+      }
+
+      // Work out which part of the texture is visible and where it goes
+      let (dwidth, dheight) = (iwidth * scale, iheight * scale);
+      let (visx, visy) = (view[0] / dwidth, view[1] / dheight);
+      panx = clamp_pan(panx, visx);
+      pany = clamp_pan(pany, visy);
+      let (visx, visy) = (visx.min(1.0), visy.min(1.0));
+      let left = (panx - visx / 2.0) * iwidth;
+      let top = (pany - visy / 2.0) * iheight;
+      // The texture is stored bottom up so the source rectangle is too
+      let source = Rect::from_corners(
+        [left, iheight - top - visy * iheight],
+        [left + visx * iwidth, iheight - top],
+      );
       widget::Image::new(image_id)
-        .middle_of(id)
-        .w_h(width, height)
+        .source_rectangle(source)
+        .x_y(area.x(), area.y())
+        .w_h(visx * dwidth, visy * dheight)
+        .parent(id)
         .graphics_for(id)
         .set(state.ids.image, ui);
+
+      // Where the whole image would be on screen, with y going up
+      let imgleft = area.x() - panx * dwidth;
+      let imgtop = area.y() + pany * dheight;
+      if state.ids.tiles.len() < tiles.len() {
+        state.update(|state| state.ids.tiles.resize(tiles.len(), &mut ui.widget_id_generator()));
+      }
+      for (tile, &tileid) in tiles.iter().zip(state.ids.tiles.iter()) {
+        let [l, t, r, b] = tile.rect;
+        let (sleft, sright) = (imgleft + l * dwidth, imgleft + r * dwidth);
+        let (stop, sbottom) = (imgtop - t * dheight, imgtop - b * dheight);
+        // Only draw the part of the tile that's in view
+        let (cleft, cright) = (sleft.max(area.left()), sright.min(area.right()));
+        let (ctop, cbottom) = (stop.min(area.top()), sbottom.max(area.bottom()));
+        if cright <= cleft || ctop <= cbottom {
+          continue
+        }
+        let (twidth, theight) = tile.dims;
+        let source = Rect::from_corners(
+          [(cleft - sleft) / (sright - sleft) * twidth, (cbottom - sbottom) / (stop - sbottom) * theight],
+          [(cright - sleft) / (sright - sleft) * twidth, (ctop - sbottom) / (stop - sbottom) * theight],
+        );
+        widget::Image::new(tile.id)
+          .source_rectangle(source)
+          .x_y((cleft + cright) / 2.0, (ctop + cbottom) / 2.0)
+          .w_h(cright - cleft, ctop - cbottom)
+          .parent(id)
+          .graphics_for(id)
+          .set(tileid, ui);
+      }
+
+      if let Some(overlay) = overlay {
+        widget::Image::new(overlay)
+          .source_rectangle(source)
+          .x_y(area.x(), area.y())
+          .w_h(visx * dwidth, visy * dheight)
+          .parent(id)
+          .graphics_for(id)
+          .set(state.ids.overlay, ui);
+      }
+
+      // The unedited render goes over the same part of the image, which may
+      // be a different size in its texture if the edits changed the crops
+      let (shown_left, shown_top) = (panx - visx / 2.0, pany - visy / 2.0);
+      let (shown_width, shown_height) = (visx * dwidth, visy * dheight);
+      let before_source = |(bwidth, bheight): (f64, f64), l: f64, r: f64| Rect::from_corners(
+        [l * bwidth, (1.0 - shown_top - visy) * bheight],
+        [r * bwidth, (1.0 - shown_top) * bheight],
+      );
+      match (newcompare, before) {
+        (Compare::Split(split), Some((before_id, before_dims))) => {
+          let divider = rect.left() + split * rect.w();
+          let shown_right = area.x() + shown_width / 2.0;
+          let left = area.x() - shown_width / 2.0;
+          let right = divider.min(shown_right);
+          if right > left {
+            let r = (right - imgleft) / dwidth;
+            widget::Image::new(before_id)
+              .source_rectangle(before_source(before_dims, shown_left, r))
+              .x_y((left + right) / 2.0, area.y())
+              .w_h(right - left, shown_height)
+              .parent(id)
+              .graphics_for(id)
+              .set(state.ids.before, ui);
+          }
+        },
+        (Compare::SideBySide, Some((before_id, before_dims))) => {
+          widget::Image::new(before_id)
+            .source_rectangle(before_source(before_dims, shown_left, shown_left + visx))
+            .x_y(area.x() - area.w(), area.y())
+            .w_h(shown_width, shown_height)
+            .parent(id)
+            .graphics_for(id)
+            .set(state.ids.before, ui);
+        },
+        _ => {},
+      }
+      if let Compare::Split(split) = newcompare {
+        widget::Rectangle::fill([2.0, rect.h()])
+          .x_y(rect.left() + split * rect.w(), rect.y())
+          .parent(id)
+          .graphics_for(id)
+          .color(conrod_core::color::WHITE)
+          .set(state.ids.divider, ui);
+      }
+
+      if newzoom != zoom || (panx, pany) != pan {
+        events.push(ViewEvent::View(newzoom, (panx, pany)));
+      }
+      if newcompare != compare {
+        events.push(ViewEvent::Compare(newcompare));
+      }
+
+      // Work out what part of the image is under the cursor
+      let hover = ui.widget_input(id).mouse().and_then(|mouse| {
+        let [x, y] = mouse.abs_xy();
+        let imgx = (x - imgleft) / dwidth;
+        let imgy = (imgtop - y) / dheight;
+        if (0.0..1.0).contains(&imgx) && (0.0..1.0).contains(&imgy) {
+          Some((imgx, imgy))
+        } else {
+          None
+        }
+      });
+      if hover != state.hover {
+        state.update(|state| state.hover = hover);
+        events.push(ViewEvent::Hover(hover));
+      }
+
+      if picking {
+        let mut current = None;
+        if let Some(mouse) = ui.widget_input(id).mouse() {
+          let [x, y] = mouse.abs_xy();
+          let pos = (
+            ((x - imgleft) / dwidth).max(0.0).min(1.0),
+            ((imgtop - y) / dheight).max(0.0).min(1.0),
+          );
+          if mouse.buttons.left().is_down() {
+            let start = state.pick.unwrap_or(pos);
+            state.update(|state| state.pick = Some(start));
+            current = Some((start, pos));
+          } else if let Some(start) = state.pick {
+            // Let go so that's the box
+            state.update(|state| state.pick = None);
+            events.push(ViewEvent::Pick((
+              start.0.min(pos.0), start.1.min(pos.1), start.0.max(pos.0), start.1.max(pos.1),
+            )));
+          }
+        }
+        if let Some((start, end)) = current {
+          let (x0, x1) = (imgleft + start.0.min(end.0) * dwidth, imgleft + start.0.max(end.0) * dwidth);
+          let (y0, y1) = (imgtop - start.1.max(end.1) * dheight, imgtop - start.1.min(end.1) * dheight);
+          widget::Rectangle::outline([(x1 - x0).max(1.0), (y1 - y0).max(1.0)])
+            .x_y((x0 + x1) / 2.0, (y0 + y1) / 2.0)
+            .parent(id)
+            .graphics_for(id)
+            .color(conrod_core::color::WHITE)
+            .set(state.ids.pick, ui);
+        }
+      }
     }
-    event
+    events
   }
 }
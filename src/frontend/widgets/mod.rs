@@ -1,7 +1,11 @@
 mod curve_editor;
 mod xy_pad;
 mod image_view;
+mod histogram;
+mod thumbnails;
 
 pub use curve_editor::CurveEditor;
 pub use xy_pad::SimplerXYPad;
-pub use image_view::ImageView;
+pub use image_view::{ImageView, Zoom, Compare, ViewEvent, ViewTile};
+pub use histogram::HistogramView;
+pub use thumbnails::{Thumbnails, Thumbnail, ThumbnailEvent};
@@ -0,0 +1,259 @@
+//! Used for browsing the images of a directory by their thumbnails
+
+use conrod_core::widget;
+use conrod_core::{color, Color, Colorable, Positionable, Sizeable, Widget};
+use conrod_core::input::Key;
+
+// Scroll distance conrod reports for a single line of the mouse wheel
+static SCROLL_LINE: f64 = 10.0;
+// Room under each thumbnail for its name
+static LABEL_HEIGHT: f64 = 18.0;
+static PADDING: f64 = 4.0;
+
+/// An image in the grid, with its texture once it has been rendered
+#[derive(Clone, Debug, PartialEq)]
+pub struct Thumbnail {
+  pub label: String,
+  pub image: Option<(conrod_core::image::Id, (f64, f64))>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ThumbnailEvent {
+  /// An image was clicked or the arrow keys moved onto it
+  Select(usize),
+  /// The range of images that are now on screen
+  Visible(usize, usize),
+}
+
+#[derive(WidgetCommon)]
+pub struct Thumbnails<'a> {
+  #[conrod(common_builder)]
+  common: widget::CommonBuilder,
+  style: Style,
+  items: &'a [Thumbnail],
+  selected: Option<usize>,
+  /// Width and height of the square each thumbnail is fitted in
+  pub size: f64,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, WidgetStyle)]
+pub struct Style {
+  /// The color of the background
+  #[conrod(default = "color::CHARCOAL")]
+  pub color: Option<Color>,
+  /// The color behind the selected image
+  #[conrod(default = "color::LIGHT_BLUE")]
+  pub selected_color: Option<Color>,
+}
+
+struct Ids {
+  rectangle: conrod_core::widget::Id,
+  selected: conrod_core::widget::Id,
+  images: conrod_core::widget::id::List,
+  labels: conrod_core::widget::id::List,
+}
+
+impl Ids {
+  pub fn new(mut generator: conrod_core::widget::id::Generator) -> Self {
+    Ids {
+      rectangle: generator.next(),
+      selected: generator.next(),
+      images: conrod_core::widget::id::List::new(),
+      labels: conrod_core::widget::id::List::new(),
+    }
+  }
+}
+
+pub struct State {
+  ids: Ids,
+  // First row on screen and how far the wheel has gone towards the next one
+  row: usize,
+  scrolled: f64,
+  selected: Option<usize>,
+  visible: (usize, usize),
+}
+
+impl<'a> Thumbnails<'a> {
+  pub fn new(items: &'a [Thumbnail], selected: Option<usize>) -> Self {
+    Self {
+      common: widget::CommonBuilder::default(),
+      style: Style::default(),
+      items,
+      selected,
+      size: 128.0,
+    }
+  }
+
+  builder_methods! {
+    pub size { size = f64 }
+  }
+}
+
+impl<'a> Widget for Thumbnails<'a> {
+  type State = State;
+  type Style = Style;
+  type Event = Vec<ThumbnailEvent>;
+
+  fn init_state(&self, id_gen: widget::id::Generator) -> Self::State {
+    State {
+      ids: Ids::new(id_gen),
+      row: 0,
+      scrolled: 0.0,
+      selected: None,
+      visible: (0, 0),
+    }
+  }
+
+  fn style(&self) -> Self::Style {
+    self.style.clone()
+  }
+
+  fn update(self, args: widget::UpdateArgs<Self>) -> Self::Event {
+    let widget::UpdateArgs {
+      id,
+      state,
+      rect,
+      style,
+      ui,
+      ..
+    } = args;
+    let Self {
+      items,
+      selected,
+      size,
+      ..
+    } = self;
+
+    let mut events = Vec::new();
+    let (cellw, cellh) = (size + PADDING * 2.0, size + LABEL_HEIGHT + PADDING * 2.0);
+    let columns = ((rect.w() / cellw).floor() as usize).max(1);
+    let rows = ((rect.h() / cellh).floor() as usize).max(1);
+    let total_rows = (items.len() + columns - 1) / columns;
+    let last_row = total_rows.saturating_sub(rows);
+
+    // Only whole rows are ever shown so nothing has to be cut at the edges
+    let mut row = state.row;
+    let mut scrolled = state.scrolled;
+    let input = ui.widget_input(id);
+    for scroll in input.scrolls() {
+      scrolled += scroll.y / SCROLL_LINE;
+      while scrolled >= 1.0 {
+        row += 1;
+        scrolled -= 1.0;
+      }
+      while scrolled <= -1.0 {
+        row = row.saturating_sub(1);
+        scrolled += 1.0;
+      }
+    }
+
+    let mut newselected = selected;
+    for press in input.presses().key() {
+      let current = newselected.unwrap_or(0);
+      let moved = match press.key {
+        Key::Left => current.checked_sub(1),
+        Key::Right => Some(current + 1),
+        Key::Up => current.checked_sub(columns),
+        Key::Down => Some(current + columns),
+        _ => continue,
+      };
+      if let Some(pos) = moved.filter(|pos| *pos < items.len()) {
+        newselected = Some(pos);
+      }
+    }
+    for click in input.clicks().left() {
+      let col = ((click.xy[0] + rect.w() / 2.0) / cellw).floor() as usize;
+      let clickrow = ((rect.h() / 2.0 - click.xy[1]) / cellh).floor() as usize;
+      let pos = (row + clickrow) * columns + col;
+      if col < columns && pos < items.len() {
+        newselected = Some(pos);
+      }
+    }
+    if newselected != selected {
+      if let Some(pos) = newselected {
+        events.push(ThumbnailEvent::Select(pos));
+      }
+    }
+
+    // Bring a selection that changed into view, wherever it came from
+    if newselected != state.selected {
+      if let Some(pos) = newselected {
+        let selrow = pos / columns;
+        if selrow < row {
+          row = selrow;
+        } else if selrow >= row + rows {
+          row = selrow + 1 - rows;
+        }
+      }
+    }
+    row = row.min(last_row);
+    if row != state.row || scrolled != state.scrolled || newselected != state.selected {
+      state.update(|state| {
+        state.row = row;
+        state.scrolled = scrolled;
+        state.selected = newselected;
+      });
+    }
+
+    let first = (row * columns).min(items.len());
+    let last = ((row + rows) * columns).min(items.len());
+    if (first, last) != state.visible {
+      state.update(|state| state.visible = (first, last));
+      events.push(ThumbnailEvent::Visible(first, last));
+    }
+
+    widget::Rectangle::fill(rect.dim())
+      .middle_of(id)
+      .graphics_for(id)
+      .color(style.color(ui.theme()))
+      .set(state.ids.rectangle, ui);
+
+    let shown = last - first;
+    if state.ids.images.len() < shown {
+      state.update(|state| {
+        state.ids.images.resize(shown, &mut ui.widget_id_generator());
+        state.ids.labels.resize(shown, &mut ui.widget_id_generator());
+      });
+    }
+    let cell_position = |pos: usize| {
+      let (col, row) = ((pos - first) % columns, (pos - first) / columns);
+      (col as f64 * cellw, row as f64 * cellh)
+    };
+    if let Some(pos) = newselected.filter(|pos| (first..last).contains(pos)) {
+      let (left, top) = cell_position(pos);
+      widget::Rectangle::fill([cellw, cellh])
+        .top_left_with_margins_on(id, top, left)
+        .graphics_for(id)
+        .color(style.selected_color(ui.theme()))
+        .set(state.ids.selected, ui);
+    }
+    for (i, item) in items[first..last].iter().enumerate() {
+      let (left, top) = cell_position(first + i);
+      if let Some((image, (width, height))) = item.image {
+        let scale = (size / width).min(size / height);
+        let (w, h) = (width * scale, height * scale);
+        widget::Image::new(image)
+          .w_h(w, h)
+          .top_left_with_margins_on(id, top + PADDING + (size - h) / 2.0, left + PADDING + (size - w) / 2.0)
+          .graphics_for(id)
+          .set(state.ids.images[i], ui);
+      }
+      widget::Text::new(&item.label)
+        .w(size)
+        .h(LABEL_HEIGHT)
+        .font_size(11)
+        .center_justify()
+        .no_line_wrap()
+        .color(color::WHITE)
+        .top_left_with_margins_on(id, top + PADDING + size, left + PADDING)
+        .graphics_for(id)
+        .set(state.ids.labels[i], ui);
+    }
+
+    events
+  }
+}
+
+impl<'a> Colorable for Thumbnails<'a> {
+  builder_method!(color { style.color = Some(Color) });
+}